@@ -0,0 +1,59 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::{QUEUE, AGING_CONFIG, milliseconds_since_timestamp, time_since_epoch, Priority, Timestamp};
+
+// How often to age queued messages, in seconds.
+const AGING_SWEEP_DELAY: u64 = 1;
+
+// How quickly messages gain priority while they wait to be delivered.
+#[derive(Default)]
+pub(crate) struct AgingConfig {
+    // Messages gain one priority for each interval they wait, in milliseconds. Zero
+    // disables aging.
+    pub(crate) interval: Timestamp,
+    // Messages never age past this priority.
+    pub(crate) ceiling: Priority,
+}
+
+impl AgingConfig {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.interval > 0
+    }
+
+    // The effective priority at `now` of a message that has aged from `base` since
+    // `since`. Messages never lose priority by aging, even if it was raised past the
+    // ceiling some other way.
+    pub(crate) fn priority(&self, base: Priority, current: Priority, since: Timestamp, now: Timestamp) -> Priority {
+        if !self.is_enabled() || base >= self.ceiling {
+            return current;
+        }
+        let waited = now.saturating_sub(since) / self.interval;
+        let aged = (base as Timestamp + waited).min(self.ceiling as Timestamp) as Priority;
+        aged.max(current)
+    }
+}
+
+// Raise the priority of messages that have been waiting. Aged priorities are only
+// kept in memory, so this is also how they are worked out again after a restart.
+pub(crate) fn age(server_started: Duration) {
+    let aging = AGING_CONFIG.lock().unwrap();
+    if !aging.is_enabled() {
+        return;
+    }
+    let aged = QUEUE.lock().expect("queue lock").age(time_since_epoch().as_millis(), &aging);
+    if aged > 0 {
+        log::debug!("{}|raised the priority of {} messages",
+            milliseconds_since_timestamp(server_started),
+            aged,
+        );
+    }
+}
+
+// Periodically raise the priority of messages that have been waiting.
+pub fn aging_loop(server_started: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(AGING_SWEEP_DELAY));
+        age(server_started);
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::{Counters, DEAD_LETTERS, milliseconds_since_timestamp, time_since_epoch, wal, dedup, history, InternalMessage, Timestamp};
+use crate::store::Queues;
+
+// A message that couldn't be delivered, along with why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DeadLetter {
+    pub(crate) message: InternalMessage,
+    pub(crate) error: String,
+    pub(crate) died: Timestamp,
+}
+
+// Messages that used up all their delivery attempts, by uuid.
+#[derive(Default)]
+pub(crate) struct DeadLetters {
+    // Zero means messages are retried forever.
+    pub(crate) max_delivery_attempts: usize,
+    // The oldest dead letters are dropped once there are this many.
+    pub(crate) limit: usize,
+    letters: HashMap<Uuid, DeadLetter>,
+}
+
+impl DeadLetters {
+    // Whether a message that failed to be delivered has any attempts left.
+    pub(crate) fn is_exhausted(&self, message: &InternalMessage) -> bool {
+        self.max_delivery_attempts > 0 && message.delivery_attempts >= self.max_delivery_attempts
+    }
+
+    // Add a dead letter, returning the oldest dead letter if it had to be dropped.
+    pub(crate) fn insert(&mut self, letter: DeadLetter) -> Option<DeadLetter> {
+        let mut dropped = None;
+        if self.limit > 0 && self.letters.len() >= self.limit {
+            let oldest = self.letters.values()
+                .min_by_key(|l| l.died)
+                .map(|l| l.message.uuid);
+            dropped = oldest.and_then(|uuid| self.letters.remove(&uuid));
+        }
+        self.letters.insert(letter.message.uuid, letter);
+        dropped
+    }
+
+    pub(crate) fn get(&self, uuid: &Uuid) -> Option<&DeadLetter> {
+        self.letters.get(uuid)
+    }
+
+    pub(crate) fn remove(&mut self, uuid: &Uuid) -> Option<DeadLetter> {
+        self.letters.remove(uuid)
+    }
+
+    // All dead letters, oldest first.
+    pub(crate) fn list(&self) -> Vec<&DeadLetter> {
+        let mut letters: Vec<&DeadLetter> = self.letters.values().collect();
+        letters.sort_by_key(|l| (l.died, l.message.uuid));
+        letters
+    }
+
+    pub(crate) fn drain(&mut self) -> Vec<DeadLetter> {
+        let mut letters: Vec<DeadLetter> = self.letters.drain().map(|(_, l)| l).collect();
+        letters.sort_by_key(|l| (l.died, l.message.uuid));
+        letters
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.letters.len()
+    }
+}
+
+// Return a message that couldn't be delivered to its queue, or move it to the
+// dead-letter queue if it has no delivery attempts left.
+pub(crate) fn requeue_or_dead_letter(
+        counters: &Counters,
+        queue: &mut Queues,
+        mut message: InternalMessage,
+        error: &str,
+        server_started: Duration,
+    ) {
+    if DEAD_LETTERS.lock().unwrap().is_exhausted(&message) {
+        log::warn!("{}|message {} failed after {} delivery attempts, moving to dead-letter queue: {}",
+            milliseconds_since_timestamp(server_started),
+            message.uuid,
+            message.delivery_attempts,
+            error,
+        );
+        dead_letter(counters, message, error, server_started);
+        return;
+    }
+    let uuid = message.uuid;
+    message.last_error = Some(error.to_string());
+    history::stop(&uuid);
+    if let Err(e) = queue.push(message) {
+        log::error!("{}|failed to return message {} to queue: {}",
+            milliseconds_since_timestamp(server_started),
+            uuid,
+            e,
+        );
+    }
+}
+
+// Move a message that has already been removed from its queue to the dead-letter queue.
+pub(crate) fn dead_letter(
+        counters: &Counters,
+        message: InternalMessage,
+        error: &str,
+        server_started: Duration,
+    ) {
+    counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+    counters.bytes.fetch_sub(message.size_in_bytes, Ordering::Relaxed);
+    counters.queue_removed(&message.queue, message.size_in_bytes);
+    counters.dead_letters.fetch_add(1, Ordering::Relaxed);
+    history::finish(&message, history::State::DeadLettered, Some(error));
+    let letter = DeadLetter {
+        message,
+        error: error.to_string(),
+        died: time_since_epoch().as_millis(),
+    };
+    let uuid = letter.message.uuid;
+    // The dead letter stays in the write-ahead log, so it survives a crash.
+    wal::log_dead_letter(&letter, server_started);
+    let dropped = DEAD_LETTERS.lock().unwrap().insert(letter);
+    if let Some(dropped) = dropped {
+        counters.dead_letters.fetch_sub(1, Ordering::Relaxed);
+        log::error!("{}|dead-letter queue is full, dropped message {}",
+            milliseconds_since_timestamp(server_started),
+            dropped.message.uuid,
+        );
+        wal::log_remove(dropped.message.uuid, server_started);
+    }
+    dedup::dropped(&uuid);
+}
@@ -0,0 +1,98 @@
+use std::collections::{HashMap, VecDeque};
+
+use uuid::Uuid;
+
+use crate::{DEDUP, time_since_epoch, InternalMessage, Timestamp};
+
+// Messages are duplicates if they have the same sha256 and are sent to the same queue.
+type Key = (String, String);
+
+// Index of queued and recently delivered messages by sha256, so that messages
+// resent by producers aren't queued twice.
+#[derive(Default)]
+pub(crate) struct Dedup {
+    // How long delivered messages are remembered, in milliseconds. Zero disables
+    // deduplication.
+    pub(crate) window: Timestamp,
+    queued: HashMap<Key, Uuid>,
+    keys: HashMap<Uuid, Key>,
+    delivered: HashMap<Key, (Uuid, Timestamp)>,
+    // Delivered messages, oldest first, so they can be forgotten once the window passes.
+    delivered_order: VecDeque<(Timestamp, Key)>,
+}
+
+impl Dedup {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.window > 0
+    }
+
+    // The uuid of a queued or recently delivered message with the same contents.
+    pub(crate) fn find(&mut self, queue: &str, sha256: &str, now: Timestamp) -> Option<Uuid> {
+        self.expire(now);
+        let key = (queue.to_string(), sha256.to_string());
+        self.queued.get(&key)
+            .or_else(|| self.delivered.get(&key).map(|(uuid, _)| uuid))
+            .copied()
+    }
+
+    pub(crate) fn queued(&mut self, message: &InternalMessage) {
+        let key = (message.queue.clone(), message.sha256.clone());
+        self.keys.insert(message.uuid, key.clone());
+        self.queued.insert(key, message.uuid);
+    }
+
+    // A message left the queue. Delivered messages are remembered for the window,
+    // others are forgotten right away so they can be sent again.
+    pub(crate) fn removed(&mut self, uuid: &Uuid, delivered: bool, now: Timestamp) {
+        let key = match self.keys.remove(uuid) {
+            Some(key) => key,
+            None => return,
+        };
+        if self.queued.get(&key) == Some(uuid) {
+            self.queued.remove(&key);
+        }
+        if delivered {
+            self.delivered.insert(key.clone(), (*uuid, now));
+            self.delivered_order.push_back((now, key));
+        }
+        self.expire(now);
+    }
+
+    // Forget messages delivered before the window.
+    fn expire(&mut self, now: Timestamp) {
+        while let Some((delivered, _)) = self.delivered_order.front() {
+            if delivered + self.window > now {
+                break;
+            }
+            let (delivered, key) = self.delivered_order.pop_front().unwrap();
+            // The same contents may have been delivered again since.
+            if self.delivered.get(&key).is_some_and(|(_, d)| *d == delivered) {
+                self.delivered.remove(&key);
+            }
+        }
+    }
+}
+
+// Record a message that was delivered.
+pub(crate) fn delivered(uuid: &Uuid) {
+    let mut dedup = DEDUP.lock().unwrap();
+    if dedup.is_enabled() {
+        dedup.removed(uuid, true, time_since_epoch().as_millis());
+    }
+}
+
+// Record a message that left the queue without being delivered.
+pub(crate) fn dropped(uuid: &Uuid) {
+    let mut dedup = DEDUP.lock().unwrap();
+    if dedup.is_enabled() {
+        dedup.removed(uuid, false, time_since_epoch().as_millis());
+    }
+}
+
+// Record a message that was added to the queue.
+pub(crate) fn queued(message: &InternalMessage) {
+    let mut dedup = DEDUP.lock().unwrap();
+    if dedup.is_enabled() {
+        dedup.queued(message);
+    }
+}
@@ -0,0 +1,479 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use crate::{InternalMessage, Priority, Timestamp};
+use crate::aging::AgingConfig;
+use crate::store::{self, QueueStore};
+use crate::wal::WalEntry;
+
+// Start a new segment file once the current one grows past 16 MiB.
+const SEGMENT_SIZE: u64 = 1024 * 1024 * 16;
+
+// Sorted so the last key is the highest priority, first queued message.
+type Key = (Priority, Reverse<u64>, Uuid);
+
+// Where a message can be found on disk.
+#[derive(Debug)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    length: usize,
+    size_in_bytes: usize,
+    // Where aging starts from, see `InternalMessage::aging_base`.
+    aging_base: (Priority, Timestamp),
+    expires: Timestamp,
+}
+
+// How much of a segment is still queued.
+#[derive(Debug, Default)]
+struct Segment {
+    messages: usize,
+    length: u64,
+}
+
+// Disk-backed queue. Messages are appended to segment files using the same format
+// as the write-ahead log, and only a small index of where to find each message is
+// kept in memory. A segment is deleted once it no longer holds any queued messages.
+#[derive(Default)]
+pub(crate) struct DiskStore {
+    directory: PathBuf,
+    limit: usize,
+    // Persistent stores record removals and are restored after a restart.
+    persistent: bool,
+    bytes: usize,
+    index: BTreeMap<Key, Location>,
+    keys: HashMap<Uuid, Key>,
+    segments: BTreeMap<u64, Segment>,
+    writer: Option<File>,
+    writer_segment: u64,
+    writer_offset: u64,
+}
+
+impl DiskStore {
+    // Open a persistent store, restoring any messages left in the directory.
+    pub(crate) fn open(directory: &str, limit: usize) -> io::Result<DiskStore> {
+        let mut store = DiskStore {
+            directory: PathBuf::from(directory),
+            limit,
+            persistent: true,
+            ..Default::default()
+        };
+        fs::create_dir_all(&store.directory)?;
+
+        let existing = store.existing_segments()?;
+        for segment in &existing {
+            store.load_segment(*segment)?;
+        }
+
+        // Copy everything still queued into new segments, then remove the old ones.
+        if let Some(last) = existing.last() {
+            store.writer_segment = *last;
+        }
+        let index = std::mem::take(&mut store.index);
+        store.keys.clear();
+        store.segments.clear();
+        store.bytes = 0;
+        for location in index.values() {
+            let message = store.read_location(location)?;
+            store.write(&message)?;
+        }
+        for segment in existing {
+            fs::remove_file(store.segment_path(segment))?;
+        }
+
+        Ok(store)
+    }
+
+    // Create a store that only lives as long as the process, discarding anything
+    // already in the directory.
+    pub(crate) fn create(directory: &str, limit: usize) -> io::Result<DiskStore> {
+        let store = DiskStore {
+            directory: PathBuf::from(directory),
+            limit,
+            persistent: false,
+            ..Default::default()
+        };
+        fs::create_dir_all(&store.directory)?;
+        for segment in store.existing_segments()? {
+            fs::remove_file(store.segment_path(segment))?;
+        }
+        Ok(store)
+    }
+
+    // The priority of the next message that will be popped.
+    pub(crate) fn highest_priority(&self) -> Option<Priority> {
+        self.index.keys().next_back().map(|key| key.0)
+    }
+
+    // Pop the highest priority message, but only if it fits in `available` bytes.
+    pub(crate) fn pop_if_fits(&mut self, available: usize) -> Option<io::Result<InternalMessage>> {
+        let key = match self.index.iter().next_back() {
+            Some((key, location)) if location.size_in_bytes <= available => *key,
+            _ => return None,
+        };
+        self.take(&key)
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.directory.join(format!("{:010}.segment", segment))
+    }
+
+    fn existing_segments(&self) -> io::Result<Vec<u64>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.extension() != Some(OsStr::new("segment")) {
+                continue;
+            }
+            if let Some(segment) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                segments.push(segment);
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    // Index all the messages in an existing segment.
+    fn load_segment(&mut self, segment: u64) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(self.segment_path(segment))?);
+        let mut offset = 0;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let length = reader.read_line(&mut line)?;
+            if length == 0 {
+                break;
+            }
+            match serde_json::from_str(&line) {
+                Ok(WalEntry::Push { message }) => {
+                    self.insert(&message, Location {
+                        segment,
+                        offset,
+                        length,
+                        size_in_bytes: message.size_in_bytes,
+                        aging_base: message.aging_base(),
+                        expires: message.expires,
+                    });
+                }
+                Ok(WalEntry::Remove { uuid }) => {
+                    if let Some(key) = self.keys.remove(&uuid) {
+                        let location = self.index.remove(&key).unwrap();
+                        self.bytes -= location.size_in_bytes;
+                    }
+                }
+                Ok(WalEntry::DeadLetter { .. }) => {
+                    log::warn!("ignoring dead letter in segment {}", segment);
+                }
+                Err(e) => {
+                    log::warn!("ignoring invalid entry in segment {}: {}", segment, e);
+                }
+            }
+            offset += length as u64;
+        }
+        Ok(())
+    }
+
+    // A message that is written again, for example with a new priority, replaces
+    // the earlier entry.
+    fn insert(&mut self, message: &InternalMessage, location: Location) {
+        let key = (message.priority, Reverse(message.sequence), message.uuid);
+        store::observe_sequence(message.sequence);
+        let segment = self.segments.entry(location.segment).or_default();
+        segment.messages += 1;
+        segment.length += location.length as u64;
+        self.bytes += location.size_in_bytes;
+        if let Some(previous) = self.keys.insert(message.uuid, key).and_then(|k| self.index.remove(&k)) {
+            self.bytes -= previous.size_in_bytes;
+            self.forget(&previous);
+        }
+        self.index.insert(key, location);
+    }
+
+    // A message at `location` is no longer queued.
+    fn forget(&mut self, location: &Location) {
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.messages -= 1;
+            segment.length -= location.length as u64;
+        }
+    }
+
+    // Append an entry to the current segment, returning where it was written.
+    fn append(&mut self, entry: &WalEntry) -> io::Result<(u64, u64, usize)> {
+        let mut line = serde_json::to_string(entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push('\n');
+        let writer = self.writer.as_mut().expect("segment writer");
+        writer.write_all(line.as_bytes())?;
+        if self.persistent {
+            writer.sync_data()?;
+        }
+        let offset = self.writer_offset;
+        self.writer_offset += line.len() as u64;
+        Ok((self.writer_segment, offset, line.len()))
+    }
+
+    // Start a new segment. If the oldest segment is mostly empty, anything still
+    // queued in it is copied forward so that it can be deleted.
+    fn roll(&mut self) -> io::Result<()> {
+        self.writer_segment += 1;
+        self.writer_offset = 0;
+        self.writer = Some(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.segment_path(self.writer_segment))?);
+        self.segments.insert(self.writer_segment, Segment::default());
+
+        let (&oldest, segment) = self.segments.iter().next().unwrap();
+        if oldest == self.writer_segment || segment.length > SEGMENT_SIZE / 4 {
+            return Ok(());
+        }
+        let keys: Vec<Key> = self.index.iter()
+            .filter(|(_, location)| location.segment == oldest)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let message = self.read_entry(&key, &self.index[&key])?;
+            let (segment, offset, length) = self.append(&WalEntry::Push { message: Box::new(message.clone()) })?;
+            self.insert(&message, Location {
+                segment,
+                offset,
+                length,
+                size_in_bytes: message.size_in_bytes,
+                aging_base: message.aging_base(),
+                expires: message.expires,
+            });
+        }
+        self.remove_empty_segments();
+        Ok(())
+    }
+
+    fn write(&mut self, message: &InternalMessage) -> io::Result<()> {
+        if self.writer.is_none() || self.writer_offset >= SEGMENT_SIZE {
+            self.roll()?;
+        }
+        let (segment, offset, length) = self.append(&WalEntry::Push { message: Box::new(message.clone()) })?;
+        self.insert(message, Location {
+            segment,
+            offset,
+            length,
+            size_in_bytes: message.size_in_bytes,
+            aging_base: message.aging_base(),
+            expires: message.expires,
+        });
+        Ok(())
+    }
+
+
+    // Delete the oldest segments once they are empty. Newer segments may hold
+    // removals for messages in older segments, so they can't be deleted first.
+    fn remove_empty_segments(&mut self) {
+        while let Some((&oldest, segment)) = self.segments.iter().next() {
+            if segment.messages > 0 {
+                break;
+            }
+            self.segments.remove(&oldest);
+            if oldest == self.writer_segment {
+                self.writer = None;
+            }
+            if let Err(e) = fs::remove_file(self.segment_path(oldest)) {
+                log::warn!("failed to remove segment {:?}: {}", self.segment_path(oldest), e);
+            }
+        }
+    }
+
+    // Remove a message from the index and read it back from disk.
+    fn take(&mut self, key: &Key) -> Option<io::Result<InternalMessage>> {
+        let location = self.index.remove(key)?;
+        self.keys.remove(&key.2);
+        self.bytes -= location.size_in_bytes;
+        let message = self.read_entry(key, &location);
+
+        if self.persistent {
+            let removed = if self.writer.is_none() || self.writer_offset >= SEGMENT_SIZE {
+                self.roll()
+            }
+            else {
+                Ok(())
+            };
+            if let Err(e) = removed.and_then(|_| self.append(&WalEntry::Remove { uuid: key.2 })) {
+                log::error!("failed to record removal of {}: {}", key.2, e);
+            }
+        }
+        self.forget(&location);
+        self.remove_empty_segments();
+
+        Some(message)
+    }
+
+    // Messages on disk aren't rewritten as they age, so their priority comes from the index.
+    fn read_entry(&self, key: &Key, location: &Location) -> io::Result<InternalMessage> {
+        let mut message = self.read_location(location)?;
+        message.priority = key.0;
+        Ok(message)
+    }
+
+    fn read_location(&self, location: &Location) -> io::Result<InternalMessage> {
+        let mut file = File::open(self.segment_path(location.segment))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut buffer = vec![0; location.length];
+        file.read_exact(&mut buffer)?;
+        match serde_json::from_slice(&buffer) {
+            Ok(WalEntry::Push { message }) => Ok(*message),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected entry")),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl QueueStore for DiskStore {
+    fn push(&mut self, message: InternalMessage) -> io::Result<()> {
+        self.write(&message)
+    }
+
+    fn pop(&mut self) -> Option<InternalMessage> {
+        loop {
+            let key = *self.index.keys().next_back()?;
+            match self.take(&key)? {
+                Ok(message) => return Some(message),
+                Err(e) => log::error!("failed to read {} from disk, dropping it: {}", key.2, e),
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<InternalMessage> {
+        let (key, location) = self.index.iter().next_back()?;
+        match self.read_entry(key, location) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                log::error!("failed to read message from disk: {}", e);
+                None
+            }
+        }
+    }
+
+    fn find(&self, uuid: &Uuid) -> Option<InternalMessage> {
+        let key = self.keys.get(uuid)?;
+        match self.read_entry(key, &self.index[key]) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                log::error!("failed to read {} from disk: {}", uuid, e);
+                None
+            }
+        }
+    }
+
+    fn list(&self, offset: usize, limit: usize, min_priority: Priority) -> Vec<InternalMessage> {
+        self.index.iter().rev()
+            .take_while(|(key, _)| key.0 >= min_priority)
+            .skip(offset)
+            .take(limit)
+            .filter_map(|(key, location)| match self.read_entry(key, location) {
+                Ok(message) => Some(message),
+                Err(e) => {
+                    log::error!("failed to read {} from disk: {}", key.2, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // The message is written again with its new priority, replacing the earlier entry.
+    fn reprioritize(&mut self, uuid: &Uuid, priority: Priority, now: Timestamp) -> Option<Priority> {
+        let key = *self.keys.get(uuid)?;
+        let mut message = match self.read_entry(&key, &self.index[&key]) {
+            Ok(m) => m,
+            Err(e) => {
+                log::error!("failed to read {} from disk: {}", uuid, e);
+                return None;
+            }
+        };
+        let previous = message.reprioritize(priority, now);
+        if let Err(e) = self.write(&message) {
+            log::error!("failed to write the new priority of {} to disk, it won't survive a restart: {}", uuid, e);
+            let mut location = self.index.remove(&key).unwrap();
+            location.aging_base = message.aging_base();
+            let new_key = (priority, key.1, key.2);
+            self.index.insert(new_key, location);
+            self.keys.insert(key.2, new_key);
+        }
+        Some(previous)
+    }
+
+    // Every message has to be read back from disk.
+    fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid> {
+        self.index.iter()
+            .filter_map(|(key, location)| match self.read_entry(key, location) {
+                Ok(message) if predicate(&message) => Some(message.uuid),
+                Ok(_) => None,
+                Err(e) => {
+                    log::error!("failed to read {} from disk: {}", key.2, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage> {
+        let key = *self.keys.get(uuid)?;
+        match self.take(&key)? {
+            Ok(message) => Some(message),
+            Err(e) => {
+                log::error!("failed to read {} from disk, dropping it: {}", uuid, e);
+                None
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn has_room(&self, size_in_bytes: usize) -> bool {
+        self.bytes + size_in_bytes <= self.limit
+    }
+
+    // Aged priorities are only kept in the index, as they can be worked out again from
+    // when each message arrived or was last reprioritized.
+    fn age(&mut self, now: Timestamp, aging: &AgingConfig) -> usize {
+        let aged: Vec<(Key, Priority)> = self.index.iter()
+            .filter_map(|(key, location)| {
+                let (base, since) = location.aging_base;
+                let priority = aging.priority(base, key.0, since, now);
+                if priority > key.0 { Some((*key, priority)) } else { None }
+            })
+            .collect();
+        for (key, priority) in &aged {
+            let location = self.index.remove(key).unwrap();
+            let aged_key = (*priority, key.1, key.2);
+            self.index.insert(aged_key, location);
+            self.keys.insert(key.2, aged_key);
+        }
+        aged.len()
+    }
+
+    fn position(&self, priority: Priority) -> (usize, usize) {
+        let same = self.index.keys().rev()
+            .skip_while(|key| key.0 > priority)
+            .take_while(|key| key.0 == priority)
+            .count();
+        let higher = self.index.keys().rev().take_while(|key| key.0 > priority).count();
+        (higher, same)
+    }
+
+    fn expired(&self, now: Timestamp) -> Vec<Uuid> {
+        self.index.iter()
+            .filter(|(_, location)| location.expires > 0 && location.expires <= now)
+            .map(|(key, _)| key.2)
+            .collect()
+    }
+}
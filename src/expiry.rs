@@ -0,0 +1,74 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::{Counters, InternalMessage, COUNTERS, QUEUE, milliseconds_since_timestamp, time_since_epoch, wal, dedup, history, dead_letter};
+
+// How often to check for expired messages, in seconds.
+const EXPIRY_SWEEP_DELAY: u64 = 1;
+
+// Set if expired messages are moved to the dead-letter queue instead of dropped.
+pub(crate) static DEAD_LETTER_EXPIRED: AtomicBool = AtomicBool::new(false);
+
+// Remove messages from the queue once they expire.
+pub fn expiry_loop(server_started: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(EXPIRY_SWEEP_DELAY));
+        sweep(server_started);
+    }
+}
+
+// Remove all messages that have expired, returning how many there were.
+pub(crate) fn sweep(server_started: Duration) -> usize {
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let expired = queue.remove_expired(time_since_epoch().as_millis());
+    if expired.is_empty() {
+        return 0;
+    }
+    let count = expired.len();
+    expire(&counters, expired, server_started);
+    count
+}
+
+// Pop messages with `pop` until one hasn't expired. Messages that expired since the
+// last sweep are removed the way the sweep removes them, so they're never handed out.
+pub(crate) fn pop_unexpired(
+        counters: &Counters,
+        server_started: Duration,
+        mut pop: impl FnMut() -> Option<InternalMessage>,
+    ) -> Option<InternalMessage> {
+    let now = time_since_epoch().as_millis();
+    loop {
+        let message = pop()?;
+        if !message.is_expired(now) {
+            return Some(message);
+        }
+        expire(counters, vec![message], server_started);
+    }
+}
+
+// Drop expired messages that have been taken out of the queue, or move them to the
+// dead-letter queue.
+fn expire(counters: &Counters, expired: Vec<InternalMessage>, server_started: Duration) {
+    let dead_letter_expired = DEAD_LETTER_EXPIRED.load(Ordering::Relaxed);
+    log::info!("{}|{} messages expired, {}",
+        milliseconds_since_timestamp(server_started),
+        expired.len(),
+        if dead_letter_expired { "moving to dead-letter queue" } else { "dropping" },
+    );
+    counters.expired.fetch_add(expired.len(), Ordering::Relaxed);
+    for message in expired {
+        if dead_letter_expired {
+            dead_letter::dead_letter(counters, message, "expired", server_started);
+        }
+        else {
+            wal::log_remove(message.uuid, server_started);
+            dedup::dropped(&message.uuid);
+            history::finish(&message, history::State::Expired, None);
+            counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+            counters.bytes.fetch_sub(message.size_in_bytes, Ordering::Relaxed);
+            counters.queue_removed(&message.queue, message.size_in_bytes);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::{HashMap, VecDeque};
+
+use uuid::Uuid;
+
+use crate::{HISTORY, time_since_epoch, InternalMessage, Priority, Timestamp};
+
+// By default remember the last 10,000 messages that left the queue.
+pub(crate) const DEFAULT_HISTORY_LIMIT: usize = 10000;
+
+// Where a message is, or how it left the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum State {
+    Queued,
+    Leased,
+    InFlight,
+    Delivered,
+    Expired,
+    Dropped,
+    DeadLettered,
+    Cancelled,
+}
+
+// A change to a message's priority through the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Reprioritized {
+    pub(crate) from: Priority,
+    pub(crate) to: Priority,
+    // When it was changed, in milliseconds since the epoch.
+    pub(crate) changed: Timestamp,
+}
+
+// What is known about a message.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct Record {
+    pub(crate) uuid: Uuid,
+    pub(crate) state: State,
+    pub(crate) queue: String,
+    pub(crate) sha256: String,
+    pub(crate) priority: Priority,
+    pub(crate) original_priority: Priority,
+    pub(crate) delivery_attempts: usize,
+    pub(crate) arrived: Timestamp,
+    pub(crate) last_error: Option<String>,
+    // Every change to the priority, oldest first.
+    pub(crate) reprioritized: Vec<Reprioritized>,
+    // When the message left the queue, if it has.
+    pub(crate) finished: Option<Timestamp>,
+}
+
+impl Record {
+    pub(crate) fn new(message: &InternalMessage, state: State) -> Record {
+        Record {
+            uuid: message.uuid,
+            state,
+            queue: message.queue.clone(),
+            sha256: message.sha256.clone(),
+            priority: message.priority,
+            original_priority: message.original_priority,
+            delivery_attempts: message.delivery_attempts,
+            arrived: message.arrived,
+            last_error: message.last_error.clone(),
+            reprioritized: message.reprioritized.clone(),
+            finished: None,
+        }
+    }
+}
+
+// Messages being delivered by the proxy or notify thread, and a bounded history of
+// messages that recently left the queue.
+#[derive(Default)]
+pub(crate) struct History {
+    pub(crate) limit: usize,
+    in_flight: HashMap<Uuid, Record>,
+    finished: HashMap<Uuid, Record>,
+    // Finished messages, oldest first, so the oldest can be forgotten.
+    order: VecDeque<Uuid>,
+}
+
+impl History {
+    pub(crate) fn get(&self, uuid: &Uuid) -> Option<&Record> {
+        self.in_flight.get(uuid).or_else(|| self.finished.get(uuid))
+    }
+
+    pub(crate) fn start(&mut self, message: &InternalMessage) {
+        self.in_flight.insert(message.uuid, Record::new(message, State::InFlight));
+    }
+
+    // A message that was being delivered was returned to the queue.
+    pub(crate) fn stop(&mut self, uuid: &Uuid) {
+        self.in_flight.remove(uuid);
+    }
+
+    pub(crate) fn finish(&mut self, mut record: Record, now: Timestamp) {
+        self.in_flight.remove(&record.uuid);
+        if self.limit == 0 {
+            return;
+        }
+        record.finished = Some(now);
+        if self.finished.insert(record.uuid, record.clone()).is_none() {
+            self.order.push_back(record.uuid);
+        }
+        while self.order.len() > self.limit {
+            if let Some(oldest) = self.order.pop_front() {
+                self.finished.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Record a message that the proxy or notify thread is delivering.
+pub(crate) fn start(message: &InternalMessage) {
+    HISTORY.lock().unwrap().start(message);
+}
+
+// Record a message that was returned to the queue.
+pub(crate) fn stop(uuid: &Uuid) {
+    HISTORY.lock().unwrap().stop(uuid);
+}
+
+// Record a message that left the queue, and why.
+pub(crate) fn finish(message: &InternalMessage, state: State, error: Option<&str>) {
+    let mut record = Record::new(message, state);
+    if let Some(error) = error {
+        record.last_error = Some(error.to_string());
+    }
+    HISTORY.lock().unwrap().finish(record, time_since_epoch().as_millis());
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+
+use uuid::Uuid;
+
+use crate::{Counters, Timestamp};
+
+// By default remember idempotency keys for 24 hours, in seconds.
+pub(crate) const DEFAULT_IDEMPOTENCY_KEY_RETENTION: usize = 60 * 60 * 24;
+
+// Keys are only unique within a queue.
+type Key = (String, String);
+
+// The response sent the first time an idempotency key was used.
+struct Response {
+    body: serde_json::Value,
+    created: Timestamp,
+    size_in_bytes: usize,
+}
+
+// Responses to requests with an idempotency key, so that a repeated request gets the
+// same response instead of queueing the message again. Until producers authenticate,
+// all producers sending to a queue share the same keys.
+#[derive(Default)]
+pub(crate) struct IdempotencyKeys {
+    // How long keys are remembered, in milliseconds.
+    pub(crate) retention: Timestamp,
+    responses: HashMap<Key, Response>,
+    // Memory used by each queue's responses, which counts against its memory limit.
+    bytes: HashMap<String, usize>,
+    // Keys, oldest first, so they can be forgotten once the retention passes.
+    order: VecDeque<(Timestamp, Key)>,
+}
+
+impl IdempotencyKeys {
+    // The response sent the first time this key was used with this queue, if it is still
+    // remembered.
+    pub(crate) fn get(&mut self, counters: &Counters, queue: &str, key: &str, now: Timestamp) -> Option<serde_json::Value> {
+        self.expire(counters, now);
+        self.responses.get(&(queue.to_string(), key.to_string())).map(|response| response.body.clone())
+    }
+
+    // The uuid of the message queued the first time this key was used.
+    pub(crate) fn uuid(&mut self, counters: &Counters, queue: &str, key: &str, now: Timestamp) -> Option<Uuid> {
+        let body = self.get(counters, queue, key, now)?;
+        body["data"]["uuid"].as_str().and_then(|uuid| Uuid::parse_str(uuid).ok())
+    }
+
+    // Remember the response to a request. Debug information is only true of the
+    // original request, so it isn't kept.
+    pub(crate) fn insert(&mut self, counters: &Counters, queue: &str, key: &str, mut body: serde_json::Value, now: Timestamp) {
+        self.expire(counters, now);
+        if let Some(body) = body.as_object_mut() {
+            body.remove("debug");
+        }
+        let size_in_bytes = std::mem::size_of::<Response>() + queue.len() + key.len() + body.to_string().len();
+        counters.idempotency_keys.fetch_add(1, Ordering::Relaxed);
+        counters.idempotency_bytes.fetch_add(size_in_bytes, Ordering::Relaxed);
+        *self.bytes.entry(queue.to_string()).or_insert(0) += size_in_bytes;
+        let key = (queue.to_string(), key.to_string());
+        let previous = self.responses.insert(key.clone(), Response {
+            body,
+            created: now,
+            size_in_bytes,
+        });
+        if let Some(previous) = previous {
+            self.forget(counters, &key.0, previous.size_in_bytes);
+        }
+        self.order.push_back((now, key));
+    }
+
+    // Memory used by the responses remembered for a queue.
+    pub(crate) fn bytes(&mut self, counters: &Counters, queue: &str, now: Timestamp) -> usize {
+        self.expire(counters, now);
+        self.bytes.get(queue).copied().unwrap_or(0)
+    }
+
+    fn forget(&mut self, counters: &Counters, queue: &str, size_in_bytes: usize) {
+        counters.idempotency_keys.fetch_sub(1, Ordering::Relaxed);
+        counters.idempotency_bytes.fetch_sub(size_in_bytes, Ordering::Relaxed);
+        if let Some(bytes) = self.bytes.get_mut(queue) {
+            *bytes -= size_in_bytes;
+        }
+    }
+
+    // Forget keys used before the retention.
+    fn expire(&mut self, counters: &Counters, now: Timestamp) {
+        while let Some((created, _)) = self.order.front() {
+            if created + self.retention > now {
+                break;
+            }
+            let (created, key) = self.order.pop_front().unwrap();
+            if self.responses.get(&key).is_some_and(|r| r.created == created) {
+                let response = self.responses.remove(&key).unwrap();
+                self.forget(counters, &key.0, response.size_in_bytes);
+            }
+        }
+    }
+}
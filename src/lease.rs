@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::{COUNTERS, QUEUE, LEASES, milliseconds_since_timestamp, time_since_epoch, dead_letter, InternalMessage, Timestamp};
+
+// How often to check for expired leases, in seconds.
+const LEASE_SWEEP_DELAY: u64 = 1;
+
+// A message handed to a consumer, hidden from the queue until it expires.
+struct Lease {
+    message: InternalMessage,
+    expires: Timestamp,
+}
+
+// Leased messages, by receipt handle.
+#[derive(Default)]
+pub(crate) struct Leases {
+    leases: HashMap<Uuid, Lease>,
+}
+
+impl Leases {
+    // Lease a message for `visibility_timeout` milliseconds, returning the receipt handle.
+    pub(crate) fn lease(&mut self, message: InternalMessage, visibility_timeout: Timestamp) -> Uuid {
+        let receipt = Uuid::new_v4();
+        self.leases.insert(receipt, Lease {
+            message,
+            expires: time_since_epoch().as_millis() + visibility_timeout,
+        });
+        receipt
+    }
+
+    pub(crate) fn contains(&self, receipt: &Uuid) -> bool {
+        self.leases.contains_key(receipt)
+    }
+
+    // Find a leased message by its uuid, rather than the receipt handle.
+    pub(crate) fn find(&self, uuid: &Uuid) -> Option<&InternalMessage> {
+        self.leases.values().map(|lease| &lease.message).find(|message| message.uuid == *uuid)
+    }
+
+    // The uuids of leased messages matching `predicate`.
+    pub(crate) fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid> {
+        self.leases.values()
+            .filter(|lease| predicate(&lease.message))
+            .map(|lease| lease.message.uuid)
+            .collect()
+    }
+
+    // End the lease on a message by its uuid, rather than the receipt handle.
+    pub(crate) fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage> {
+        let receipt = *self.leases.iter().find(|(_, lease)| lease.message.uuid == *uuid)?.0;
+        self.take(&receipt)
+    }
+
+    // End a lease, returning the message if the lease hasn't expired.
+    pub(crate) fn take(&mut self, receipt: &Uuid) -> Option<InternalMessage> {
+        self.leases.remove(receipt).map(|lease| lease.message)
+    }
+
+    // End all leases that expired before `now`.
+    pub(crate) fn take_expired(&mut self, now: Timestamp) -> Vec<InternalMessage> {
+        let expired: Vec<Uuid> = self.leases.iter()
+            .filter(|(_, lease)| lease.expires <= now)
+            .map(|(receipt, _)| *receipt)
+            .collect();
+        expired.iter().filter_map(|receipt| self.take(receipt)).collect()
+    }
+
+    // Copies of every leased message.
+    pub(crate) fn messages(&self) -> Vec<InternalMessage> {
+        self.leases.values().map(|lease| lease.message.clone()).collect()
+    }
+
+    // End all leases.
+    pub(crate) fn drain(&mut self) -> Vec<InternalMessage> {
+        self.leases.drain().map(|(_, lease)| lease.message).collect()
+    }
+}
+
+// Return messages to the queue when their lease expires.
+pub fn lease_loop(server_started: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(LEASE_SWEEP_DELAY));
+
+        let counters = COUNTERS.lock().unwrap();
+        let mut queue = QUEUE.lock().expect("queue lock");
+        let expired = LEASES.lock().unwrap().take_expired(time_since_epoch().as_millis());
+        if expired.is_empty() {
+            continue;
+        }
+        log::info!("{}|{} leases expired, returning messages to queue",
+            milliseconds_since_timestamp(server_started),
+            expired.len(),
+        );
+        counters.leased.fetch_sub(expired.len(), Ordering::Relaxed);
+        for mut message in expired {
+            message.delivery_attempts += 1;
+            dead_letter::requeue_or_dead_letter(&counters, &mut queue, message, "lease expired", server_started);
+        }
+    }
+}
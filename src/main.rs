@@ -10,25 +10,45 @@
 //#[cfg(feature = "rqueue-notify")] mod notify;
 mod proxy;
 mod notify;
+mod wal;
+mod store;
+mod disk;
+mod shutdown;
+mod lease;
+mod dead_letter;
+mod retry;
+mod expiry;
+mod aging;
+mod dedup;
+mod idempotency;
+mod history;
+mod wakeup;
+mod stream;
+mod upstream;
 
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::sync::{Mutex, Arc};
-use std::time::{SystemTime, Duration};
+use std::time::{SystemTime, Duration, Instant};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::process;
 
-use rocket::{State, Request, response};
+use rocket::{State, Request, Response, response};
 use rocket::fairing::AdHoc;
 use rocket::http::{ContentType, Status};
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest};
 use rocket_contrib::json::{Json, JsonValue};
 
-use priority_queue::PriorityQueue;
 use uuid::Uuid;
 use sha2::{Sha256, Digest};
 use size::{Base, Size, Style};
-use rqpush::Message;
+
+use store::{QueueStore, MemoryStore, ScheduledStore, Queues, DEFAULT_QUEUE};
+use disk::DiskStore;
 
 type Priority = u8;
 type Timestamp = u128;
@@ -36,13 +56,29 @@ type SizeInBytes = AtomicUsize;
 
 // By default limit queue size to ~64 MiB
 const DEFAULT_MAXIMUM_QUEUE_SIZE: usize = 1024 * 1024 * 64;
+// By default limit messages stored on disk to ~1 GiB
+const DEFAULT_MAXIMUM_DISK_SIZE: usize = 1024 * 1024 * 1024;
 // Default priority to 10 if not otherwise set
 const DEFAULT_PRIORITY: u8 = 10;
 // By default wait 5 seconds after checking an empty queue
 const DEFAULT_DELAY: usize = 5;
+// Long-polling GET requests wait at most 60 seconds for a message
+const MAX_WAIT: u64 = 60;
+// Listing the queue returns 100 messages unless asked for more, and at most 1,000
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+// Streams have 10 messages waiting to be acknowledged unless asked for more, and at
+// most 1,000
+const DEFAULT_STREAM_CREDITS: usize = 10;
+const MAX_STREAM_CREDITS: usize = 1000;
+const STREAM_CHUNK_SIZE: u64 = 64 * 1024;
+// By default hide leased messages for 30 seconds
+const DEFAULT_VISIBILITY_TIMEOUT: usize = 30;
+// By default keep up to 10,000 messages in the dead-letter queue
+const DEFAULT_DEAD_LETTER_LIMIT: usize = 10000;
 
 // This defines the format of the message we track internally.
-#[derive(PartialEq, Eq, Hash, Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 struct InternalMessage {
     size_in_bytes: usize,
     contents: String,
@@ -51,7 +87,100 @@ struct InternalMessage {
     arrived: Timestamp,
     uuid: Uuid,
     delivery_attempts: usize,
+    // Why the last delivery attempt failed.
+    #[serde(default)]
+    last_error: Option<String>,
     original_priority: Priority,
+    // Messages with the same priority are delivered in the order they were numbered.
+    #[serde(default)]
+    sequence: u64,
+    // The message won't be delivered before this time, in milliseconds since the epoch.
+    #[serde(default)]
+    not_before: Timestamp,
+    // The message is dropped if it hasn't been delivered by this time, in milliseconds
+    // since the epoch. Zero means it never expires.
+    #[serde(default)]
+    expires: Timestamp,
+    // Messages logged before named queues were added belong to the default queue.
+    #[serde(default = "default_queue")]
+    queue: String,
+    // Changes to the priority through the API.
+    #[serde(default)]
+    reprioritized: Vec<history::Reprioritized>,
+}
+
+// A message posted to a queue, as an `rqpush::Message` that can optionally be
+// scheduled for later delivery.
+#[derive(Debug, Default, Deserialize)]
+struct IncomingMessage {
+    sha256: Option<String>,
+    contents: String,
+    priority: Option<u8>,
+    // Don't deliver the message before this time, in milliseconds since the epoch.
+    deliver_at: Option<u64>,
+    // Don't deliver the message until this many milliseconds after it arrives.
+    delay_ms: Option<u64>,
+    // Drop the message if it hasn't been delivered by this time, in milliseconds since
+    // the epoch.
+    expires_at: Option<u64>,
+    // Drop the message if it hasn't been delivered this many milliseconds after it arrives.
+    ttl_ms: Option<u64>,
+    // Resending a message with the same key returns the original response, the
+    // Idempotency-Key header can be used instead.
+    idempotency_key: Option<String>,
+}
+
+fn default_queue() -> String {
+    DEFAULT_QUEUE.to_string()
+}
+
+// Messages are uniquely identified by their uuid, allowing them to be looked up
+// in the queue by uuid alone.
+impl PartialEq for InternalMessage {
+    fn eq(&self, other: &InternalMessage) -> bool {
+        self.uuid == other.uuid
+    }
+}
+
+impl Eq for InternalMessage {}
+
+impl Hash for InternalMessage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.uuid.hash(state);
+    }
+}
+
+impl InternalMessage {
+    fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires > 0 && self.expires <= now
+    }
+
+    // The priority a message ages from, and since when. A change through the API
+    // replaces the original priority, so that a demoted message stays demoted.
+    fn aging_base(&self) -> (Priority, Timestamp) {
+        match self.reprioritized.last() {
+            Some(change) => (change.to, change.changed),
+            None => (self.original_priority, self.arrived),
+        }
+    }
+
+    // Change the priority, recording when and from what. Returns the previous priority.
+    fn reprioritize(&mut self, priority: Priority, now: Timestamp) -> Priority {
+        let previous = self.priority;
+        self.priority = priority;
+        self.reprioritized.push(history::Reprioritized {
+            from: previous,
+            to: priority,
+            changed: now,
+        });
+        previous
+    }
+}
+
+impl Borrow<Uuid> for InternalMessage {
+    fn borrow(&self) -> &Uuid {
+        &self.uuid
+    }
 }
 
 // Global counters:
@@ -63,20 +192,80 @@ struct Counters {
     proxied: AtomicUsize,
     in_queue: AtomicUsize,
     bytes: AtomicUsize,
+    leased: AtomicUsize,
+    dead_letters: AtomicUsize,
+    expired: AtomicUsize,
+    // Remembered idempotency keys, and the memory used by their responses.
+    idempotency_keys: AtomicUsize,
+    idempotency_bytes: AtomicUsize,
+    queues: HashMap<String, QueueCounters>,
+}
+
+// Per-queue counters, the global counters are totals across all queues:
+#[derive(Default)]
+struct QueueCounters {
+    queued: AtomicUsize,
+    proxied: AtomicUsize,
+    in_queue: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Counters {
+    // A message was added to the named queue.
+    fn queue_queued(&self, queue: &str, size_in_bytes: usize) {
+        if let Some(counters) = self.queues.get(queue) {
+            counters.queued.fetch_add(1, Ordering::Relaxed);
+            counters.in_queue.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(size_in_bytes, Ordering::Relaxed);
+        }
+    }
+
+    // A message was removed from the named queue and delivered.
+    fn queue_proxied(&self, queue: &str, size_in_bytes: usize) {
+        if let Some(counters) = self.queues.get(queue) {
+            counters.proxied.fetch_add(1, Ordering::Relaxed);
+            counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+            counters.bytes.fetch_sub(size_in_bytes, Ordering::Relaxed);
+        }
+    }
+
+    // A message was removed from the named queue without being delivered.
+    fn queue_removed(&self, queue: &str, size_in_bytes: usize) {
+        if let Some(counters) = self.queues.get(queue) {
+            counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+            counters.bytes.fetch_sub(size_in_bytes, Ordering::Relaxed);
+        }
+    }
+
+    // A message from a previous run was restored to the named queue.
+    fn queue_restored(&self, queue: &str, size_in_bytes: usize) {
+        if let Some(counters) = self.queues.get(queue) {
+            counters.in_queue.fetch_add(1, Ordering::Relaxed);
+            counters.bytes.fetch_add(size_in_bytes, Ordering::Relaxed);
+        }
+    }
 }
 
 // Queue configuration:
 #[derive(Default)]
 struct QueueConfig {
-    memory_limit: usize,
+    // Memory limit of each queue, by name.
+    memory_limits: HashMap<String, usize>,
     require_sha256: bool,
     shared_secret: String,
+    // If enabled, GET leases messages until they are acknowledged.
+    lease_mode: bool,
+    visibility_timeout: usize,
+    // If enabled, contents are never shown when peeking at or listing the queue.
+    redact_contents: bool,
 }
 // Proxy configuration:
 #[derive(Default)]
 struct ProxyConfig {
     delay: usize,
-    server: String,
+    upstreams: upstream::Upstreams,
+    // Whether messages rejected with a 4xx are dead-lettered, or dropped.
+    dead_letter_client_errors: bool,
 }
 // Notify configuration:
 #[derive(Default)]
@@ -95,6 +284,8 @@ struct NotifyConfig {
 struct Started(Duration);
 #[derive(Clone, Debug)]
 pub struct RequestTimer(Duration);
+// The Idempotency-Key header, if set.
+pub struct IdempotencyKey(Option<String>);
 
 // Set an HTTP status when responding with JSON objects
 #[derive(Debug)]
@@ -122,11 +313,32 @@ impl<'a, 'r> FromRequest<'a, 'r> for RequestTimer {
     }
 }
 
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = std::convert::Infallible;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let key = request.headers().get_one("Idempotency-Key").map(|k| k.to_string());
+        Outcome::Success(IdempotencyKey(key))
+    }
+}
+
 lazy_static! {
     static ref COUNTERS: Arc<Mutex<Counters>> = Arc::new(Mutex::new(Counters::default()));
-    static ref QUEUE: Arc<Mutex<PriorityQueue<InternalMessage, Priority>>> = Arc::new(Mutex::new(PriorityQueue::<InternalMessage, Priority>::new()));
+    static ref QUEUE: Arc<Mutex<Queues>> = Arc::new(Mutex::new(Queues::new()));
     static ref PROXY_CONFIG: Arc<Mutex<ProxyConfig>> = Arc::new(Mutex::new(ProxyConfig::default()));
     static ref NOTIFY_CONFIG: Arc<Mutex<NotifyConfig>> = Arc::new(Mutex::new(NotifyConfig::default()));
+    static ref WAL: Arc<Mutex<wal::WriteAheadLog>> = Arc::new(Mutex::new(wal::WriteAheadLog::default()));
+    // Held by the proxy and notify loops while delivering a message.
+    static ref DELIVERY: Arc<Mutex<()>> = Arc::new(Mutex::new(()));
+    static ref SNAPSHOT_PATH: Arc<Mutex<String>> = Arc::new(Mutex::new(String::new()));
+    static ref LEASES: Arc<Mutex<lease::Leases>> = Arc::new(Mutex::new(lease::Leases::default()));
+    static ref DEAD_LETTERS: Arc<Mutex<dead_letter::DeadLetters>> = Arc::new(Mutex::new(dead_letter::DeadLetters::default()));
+    static ref RETRY_CONFIG: Arc<Mutex<retry::RetryConfig>> = Arc::new(Mutex::new(retry::RetryConfig::default()));
+    static ref DEDUP: Arc<Mutex<dedup::Dedup>> = Arc::new(Mutex::new(dedup::Dedup::default()));
+    static ref IDEMPOTENCY_KEYS: Arc<Mutex<idempotency::IdempotencyKeys>> = Arc::new(Mutex::new(idempotency::IdempotencyKeys::default()));
+    static ref HISTORY: Arc<Mutex<history::History>> = Arc::new(Mutex::new(history::History::default()));
+    static ref AGING_CONFIG: Arc<Mutex<aging::AgingConfig>> = Arc::new(Mutex::new(aging::AgingConfig::default()));
+    static ref WAKEUP: wakeup::Wakeup = wakeup::Wakeup::default();
 }
 
 // Helper function for getting time since the epoch in milliseconds.
@@ -150,7 +362,32 @@ fn milliseconds_since_timestamp(timestamp: Duration) -> usize {
 // Accept incoming messages for the proxy to queue.
 #[post("/", format="json", data="<message>")]
 fn new(
-        message: Json<Message>,
+        message: Json<IncomingMessage>,
+        idempotency_key: IdempotencyKey,
+        server_started: State<Started>,
+        request_started: RequestTimer,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    queue_message(DEFAULT_QUEUE, message, idempotency_key, server_started, request_started, queue_config)
+}
+
+// Accept incoming messages for a named queue.
+#[post("/queues/<name>", format="json", data="<message>")]
+fn new_named(
+        name: String,
+        message: Json<IncomingMessage>,
+        idempotency_key: IdempotencyKey,
+        server_started: State<Started>,
+        request_started: RequestTimer,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    queue_message(&name, message, idempotency_key, server_started, request_started, queue_config)
+}
+
+fn queue_message(
+        name: &str,
+        message: Json<IncomingMessage>,
+        idempotency_key: IdempotencyKey,
         server_started: State<Started>,
         request_started: RequestTimer,
         queue_config: State<QueueConfig>,
@@ -159,82 +396,91 @@ fn new(
     // A POST was routed here, requesting to add something to the queue.
     let queue_requests = counters.queue_requests.fetch_add(1, Ordering::Relaxed) + 1;
 
-    // Generate a Sha256 of the message contents.
-    let mut hasher = Sha256::new();
-    hasher.input(message.0.contents.as_bytes());
-    if queue_config.shared_secret != "" {
-        hasher.input(queue_config.shared_secret.as_bytes());
+    // Only configured queues can be used.
+    let memory_limit = match queue_config.memory_limits.get(name) {
+        Some(limit) => *limit,
+        None => {
+            log::info!("{}|unknown queue '{}', ignoring message",
+                milliseconds_since_timestamp(server_started.0),
+                name,
+            );
+            return unknown_queue(name, server_started.0, request_started.0);
+        }
+    };
+
+    if shutdown::is_shutting_down() {
+        log::info!("{}|shutting down, ignoring message",
+            milliseconds_since_timestamp(server_started.0),
+        );
+        return shutting_down(server_started.0, request_started.0);
+    }
+
+    // A request that is repeated with the same idempotency key gets the same response.
+    let idempotency_key = idempotency_key.0.or_else(|| message.0.idempotency_key.clone());
+    if let Some(key) = idempotency_key.as_ref() {
+        let original = IDEMPOTENCY_KEYS.lock().unwrap().get(&counters, name, key, time_since_epoch().as_millis());
+        if let Some(mut body) = original {
+            log::info!("{}|idempotency key '{}' was already used, returning the original response",
+                milliseconds_since_timestamp(server_started.0),
+                key,
+            );
+            let debug;
+            if cfg!(feature = "rqueue-debug") {
+                debug = json!({
+                    "uptime": milliseconds_since_timestamp(server_started.0),
+                    "process_time": milliseconds_since_timestamp(request_started.0),
+                    "idempotency_key": key,
+                })
+            }
+            else {
+                debug = json!({})
+            }
+            body["debug"] = debug.0;
+            return QueueApiResponse {
+                json: JsonValue(body),
+                status: Status::Accepted,
+            };
+        }
     }
-    let sha256 = format!("{:x}", hasher.result());
+
+    // Generate a Sha256 of the message contents, and if a Sha256 was provided, validate it.
+    let sha256 = match check_sha256(&message.0.contents, message.0.sha256.as_deref(), &queue_config) {
+        Ok(sha256) => sha256,
+        Err((reason, sha256)) => {
+            log::info!("{}|{}, expected {}, ignoring message",
+                milliseconds_since_timestamp(server_started.0),
+                reason,
+                sha256,
+            );
+            let debug;
+            if cfg!(feature = "rqueue-debug") {
+                debug = json!({
+                    "uptime": milliseconds_since_timestamp(server_started.0),
+                    "process_time": milliseconds_since_timestamp(request_started.0),
+                    "expected_sha256": sha256,
+                    "received_sha256": message.0.sha256,
+                })
+            }
+            else {
+                debug = json!({})
+            }
+            return QueueApiResponse {
+                json: json!({
+                        "status": "bad request",
+                        "reason": reason,
+                        "code": 400,
+                        "debug": debug,
+                    }),
+                status: Status::BadRequest,
+            };
+        }
+    };
     log::debug!("{}|generated sha256{} for message '{}'",
         milliseconds_since_timestamp(server_started.0),
         sha256,
         message.0.contents,
     );
 
-    // If a Sha256 was provided, validate it
-    match message.0.sha256 {
-        None => {
-            if queue_config.require_sha256 {
-                log::warn!("{}|sha256 required but not set, expected {}, ignoring message",
-                    milliseconds_since_timestamp(server_started.0),
-                    sha256,
-                );
-                let debug;
-                if cfg!(feature = "rqueue-debug") {
-                    debug = json!({
-                        "uptime": milliseconds_since_timestamp(server_started.0),
-                        "process_time": milliseconds_since_timestamp(request_started.0),
-                        "expected_sha256": sha256,
-                    })
-                }
-                else {
-                    debug = json!({})
-                }
-                return QueueApiResponse {
-                    json: json!({
-                            "status": "bad request",
-                            "reason": "required sha256 not set",
-                            "code": 400,
-                            "debug": debug,
-                        }),
-                    status: Status::BadRequest,
-                };
-            }
-        },
-        _ => {
-            let sha256_received = message.0.sha256.unwrap();
-            if sha256 != sha256_received.to_lowercase() {
-                log::info!("{}|invalid sha256 {} received, expected {}, ignoring message",
-                    milliseconds_since_timestamp(server_started.0),
-                    sha256_received,
-                    sha256,
-                );
-                let debug;
-                if cfg!(feature = "rqueue-debug") {
-                    debug = json!({
-                        "uptime": milliseconds_since_timestamp(server_started.0),
-                        "process_time": milliseconds_since_timestamp(request_started.0),
-                        "expected_sha256": sha256,
-                        "received_sha256": sha256_received,
-                    })
-                }
-                else {
-                    debug = json!({})
-                }
-                return QueueApiResponse {
-                    json: json!({
-                            "status": "bad request",
-                            "reason": "invalid sha256",
-                            "code": 400,
-                            "debug": debug,
-                        }),
-                    status: Status::BadRequest,
-                };
-            }
-        },
-    }
-
     // Priority is optional, set a default if not provided.
     let priority: Priority;
     match message.0.priority {
@@ -310,24 +556,150 @@ fn new(
             }
         }
     }
+    // Producers may resend a message that is still queued, or was just delivered.
+    let arrived = time_since_epoch().as_millis();
+    let duplicate = {
+        let mut dedup = DEDUP.lock().unwrap();
+        if dedup.is_enabled() { dedup.find(name, &sha256, arrived) } else { None }
+    };
+    if let Some(uuid) = duplicate {
+        log::info!("{}|message with sha256 {} is a duplicate of {}, ignoring it",
+            milliseconds_since_timestamp(server_started.0),
+            sha256,
+            uuid,
+        );
+        let debug;
+        if cfg!(feature = "rqueue-debug") {
+            debug = json!({
+                "uptime": milliseconds_since_timestamp(server_started.0),
+                "process_time": milliseconds_since_timestamp(request_started.0),
+            })
+        }
+        else {
+            debug = json!({})
+        }
+        return QueueApiResponse {
+            json: json!({
+                    "status": "duplicate",
+                    "code": 200,
+                    "data": {
+                        "uuid": uuid,
+                        "sha256": sha256,
+                        "queue": name,
+                    },
+                    "debug": debug,
+                }),
+            status: Status::Ok,
+        };
+    }
+
+    // Delivery can be scheduled for a specific time, or after a delay, but not both.
+    let not_before = match (message.0.deliver_at, message.0.delay_ms) {
+        (None, None) => 0,
+        (Some(deliver_at), None) => deliver_at as Timestamp,
+        (None, Some(delay_ms)) => arrived + delay_ms as Timestamp,
+        (Some(deliver_at), Some(delay_ms)) => {
+            log::info!("{}|received both deliver_at of {} and delay_ms of {}",
+                milliseconds_since_timestamp(server_started.0),
+                deliver_at,
+                delay_ms,
+            );
+            let debug;
+            if cfg!(feature = "rqueue-debug") {
+                debug = json!({
+                    "uptime": milliseconds_since_timestamp(server_started.0),
+                    "process_time": milliseconds_since_timestamp(request_started.0),
+                    "received_deliver_at": deliver_at,
+                    "received_delay_ms": delay_ms,
+                })
+            }
+            else {
+                debug = json!({})
+            }
+            return QueueApiResponse {
+                json: json!({
+                        "status": "bad request",
+                        "reason": "set either deliver_at or delay_ms, not both",
+                        "code": 400,
+                        "debug": debug,
+                    }),
+                status: Status::BadRequest,
+            };
+        }
+    };
+    if not_before > arrived {
+        log::debug!("{}|message won't be delivered for {} ms",
+            milliseconds_since_timestamp(server_started.0),
+            not_before - arrived,
+        );
+    }
+
+    // Likewise, messages can expire at a specific time or after a delay.
+    let expires = match (message.0.expires_at, message.0.ttl_ms) {
+        (None, None) => 0,
+        (Some(expires_at), None) => expires_at as Timestamp,
+        (None, Some(ttl_ms)) => arrived + ttl_ms as Timestamp,
+        (Some(expires_at), Some(ttl_ms)) => {
+            log::info!("{}|received both expires_at of {} and ttl_ms of {}",
+                milliseconds_since_timestamp(server_started.0),
+                expires_at,
+                ttl_ms,
+            );
+            let debug;
+            if cfg!(feature = "rqueue-debug") {
+                debug = json!({
+                    "uptime": milliseconds_since_timestamp(server_started.0),
+                    "process_time": milliseconds_since_timestamp(request_started.0),
+                    "received_expires_at": expires_at,
+                    "received_ttl_ms": ttl_ms,
+                })
+            }
+            else {
+                debug = json!({})
+            }
+            return QueueApiResponse {
+                json: json!({
+                        "status": "bad request",
+                        "reason": "set either expires_at or ttl_ms, not both",
+                        "code": 400,
+                        "debug": debug,
+                    }),
+                status: Status::BadRequest,
+            };
+        }
+    };
+
     // Internal state, the queue 
     let internal = InternalMessage {
         // Size required is the size of this struct, plus the capacity of both contained strings
-        size_in_bytes: std::mem::size_of::<InternalMessage>() + message.0.contents.capacity() + sha256.capacity(),
+        size_in_bytes: std::mem::size_of::<InternalMessage>() + message.0.contents.capacity() + sha256.capacity() + name.len(),
         contents: message.0.contents,
         sha256: sha256,
         priority: priority,
-        arrived: time_since_epoch().as_millis(),
+        arrived,
         uuid: Uuid::new_v4(),
         delivery_attempts: 0,
+        last_error: None,
         original_priority: priority,
+        sequence: store::next_sequence(),
+        not_before,
+        expires,
+        queue: name.to_string(),
+        reprioritized: Vec::new(),
     };
-    let bytes_allocated_for_queue = counters.bytes.load(Ordering::Relaxed);
-    if (bytes_allocated_for_queue + internal.size_in_bytes) > queue_config.memory_limit {
-        log::warn!("{}|queue is holding {}, limit of {}, unable to store additional {}",
+    // Grab lock and add message to queue
+    let mut queue = QUEUE.lock().expect("queue lock");
+
+    let store = queue.get(name).expect("configured queue");
+    let bytes_allocated_for_queue = store.bytes();
+    // Remembered idempotency keys use the queue's memory too.
+    let idempotency_bytes = IDEMPOTENCY_KEYS.lock().unwrap().bytes(&counters, name, arrived);
+    if !store.has_room(internal.size_in_bytes + idempotency_bytes) {
+        log::warn!("{}|queue '{}' is holding {}, limit of {}, unable to store additional {}",
             milliseconds_since_timestamp(server_started.0),
+            name,
             Size::Bytes(bytes_allocated_for_queue),
-            Size::Bytes(queue_config.memory_limit),
+            Size::Bytes(memory_limit),
             Size::Bytes(internal.size_in_bytes)
         );
         let debug;
@@ -337,7 +709,9 @@ fn new(
                 "process_time": milliseconds_since_timestamp(request_started.0),
                 "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
                 "request_size": format!("{}", Size::Bytes(internal.size_in_bytes)),
-                "max_bytes": format!("{}", Size::Bytes(queue_config.memory_limit)),
+                "max_bytes": format!("{}", Size::Bytes(memory_limit)),
+                "overflow_size": format!("{}", Size::Bytes(store.overflow().1)),
+                "idempotency_size": format!("{}", Size::Bytes(idempotency_bytes)),
             })
         }
         else {
@@ -357,22 +731,82 @@ fn new(
     // Clone this so we can increment bytes_allocated_for_queue
     let size_of_request = internal.size_in_bytes.clone();
 
-    // Grab lock and add message to queue
-    let mut queue = QUEUE.lock().expect("queue lock");
-    queue.push(internal, priority);
+    // The message must be written to disk before it is acknowledged.
+    if let Err(e) = wal::log_push(&internal) {
+        log::error!("{}|failed to write message to write-ahead log: {}",
+            milliseconds_since_timestamp(server_started.0),
+            e,
+        );
+        let debug;
+        if cfg!(feature = "rqueue-debug") {
+            debug = json!({
+                "uptime": milliseconds_since_timestamp(server_started.0),
+                "process_time": milliseconds_since_timestamp(request_started.0),
+                "error": e.to_string(),
+            })
+        }
+        else {
+            debug = json!({})
+        }
+        return QueueApiResponse {
+            json: json!({
+                    "status": "service unavailable",
+                    "reason": "unable to persist message",
+                    "code": 503,
+                    "debug": debug,
+                }),
+            status: Status::ServiceUnavailable,
+        };
+    }
+    let uuid = internal.uuid;
+    let sha256 = internal.sha256.clone();
+    // Where the message will be in the queue, only approximate as messages can age.
+    let (higher, same) = queue.get(name).expect("configured queue").position(priority);
+    dedup::queued(&internal);
+    if let Err(e) = queue.push(internal) {
+        wal::log_remove(uuid, server_started.0);
+        dedup::dropped(&uuid);
+        log::error!("{}|failed to store message: {}",
+            milliseconds_since_timestamp(server_started.0),
+            e,
+        );
+        let debug;
+        if cfg!(feature = "rqueue-debug") {
+            debug = json!({
+                "uptime": milliseconds_since_timestamp(server_started.0),
+                "process_time": milliseconds_since_timestamp(request_started.0),
+                "error": e.to_string(),
+            })
+        }
+        else {
+            debug = json!({})
+        }
+        return QueueApiResponse {
+            json: json!({
+                    "status": "service unavailable",
+                    "reason": "unable to persist message",
+                    "code": 503,
+                    "debug": debug,
+                }),
+            status: Status::ServiceUnavailable,
+        };
+    }
 
     // A message has been sucessfully added to the queue.
     let queued = counters.queued.fetch_add(1, Ordering::Relaxed) + 1;
     let in_queue = counters.in_queue.fetch_add(1, Ordering::Relaxed) + 1;
     let bytes_allocated_for_queue = counters.bytes.fetch_add(size_of_request, Ordering::Relaxed) + size_of_request;
+    counters.queue_queued(name, size_of_request);
+    let (in_overflow, overflow_bytes) = queue.get(name).expect("configured queue").overflow();
     // Retreive other debug statistics
     let proxy_requests = counters.proxy_requests.load(Ordering::Relaxed);
     let proxied = counters.proxied.load(Ordering::Relaxed);
 
-    log::info!("{}|{} message with priority of {} queued, {} queue_requests, {} queued, {} proxy requests, {} proxied, {} in {} queue, request took {} ms",
+    log::info!("{}|{} message with priority of {} queued in '{}', {} queue_requests, {} queued, {} proxy requests, {} proxied, {} in {} queue, request took {} ms",
         milliseconds_since_timestamp(server_started.0),
         Size::Bytes(size_of_request).to_string(Base::Base10, Style::Abbreviated),
         priority,
+        name,
         queue_requests,
         queued,
         proxy_requests,
@@ -394,50 +828,713 @@ fn new(
             "process_time": milliseconds_since_timestamp(request_started.0),
             "request_size": format!("{}", Size::Bytes(size_of_request)),
             "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+            "queue": name,
+            "in_overflow": in_overflow,
+            "overflow_size": format!("{}", Size::Bytes(overflow_bytes)),
+            "idempotency_keys": counters.idempotency_keys.load(Ordering::Relaxed),
+            "idempotency_size": format!("{}", Size::Bytes(counters.idempotency_bytes.load(Ordering::Relaxed))),
         })
     }
     else {
         debug = json!({})
     }
+    let response = json!({
+        "status": "accepted",
+        "code": 202,
+        "data": {
+            "uuid": uuid,
+            "sha256": sha256,
+            "priority": priority,
+            "queue": name,
+            "position": higher + same + 1,
+            "depth": same + 1,
+        },
+        "debug": debug,
+    });
+    if let Some(key) = idempotency_key.as_ref() {
+        IDEMPOTENCY_KEYS.lock().unwrap().insert(&counters, name, key, response.0.clone(), time_since_epoch().as_millis());
+    }
     QueueApiResponse {
-        json: json!({
-                "status": "accepted",
-                "code": 202,
-                "debug": debug,
-            }),
+        json: response,
         status: Status::Accepted,
     }
 }
 
-// Temporary: ultimately the proxy will push this data.
-#[get("/", format = "json")]
-fn get(
-        request_started: RequestTimer,
-        server_started: State<Started>,
-    ) -> Option<QueueApiResponse> {
-    let counters = COUNTERS.lock().unwrap();
-    // A GET was routed here, requesting to get something from the queue.
-    let proxy_requests = counters.proxy_requests.fetch_add(1, Ordering::Relaxed) + 1;
+// The outcome of checking one message in a batch.
+enum BatchEntry {
+    Queue(Box<InternalMessage>, Option<String>),
+    // The message is already queued, or was recently delivered.
+    Duplicate(Uuid, String),
+    // The idempotency key was already used, this is the original response.
+    Repeated(serde_json::Value),
+    Invalid(String),
+}
 
-    let mut queue = QUEUE.lock().expect("queue lock");
-    queue.pop().map(|internal| {
-        // A message has been sucessfully removed from the queue.
-        let proxied = counters.proxied.fetch_add(1, Ordering::Relaxed) + 1;
-        let in_queue = counters.in_queue.fetch_sub(1, Ordering::Relaxed) - 1;
-        let bytes_allocated_for_queue = counters.bytes.fetch_sub(internal.0.size_in_bytes, Ordering::Relaxed) - internal.0.size_in_bytes;
-        // Retreive other debug statistics
-        let queue_requests = counters.queue_requests.load(Ordering::Relaxed);
-        let queued = counters.queued.load(Ordering::Relaxed);
+// Generate a Sha256 of the message contents, including the shared secret if there is one.
+fn hash_contents(contents: &str, shared_secret: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(contents.as_bytes());
+    if !shared_secret.is_empty() {
+        hasher.input(shared_secret.as_bytes());
+    }
+    format!("{:x}", hasher.result())
+}
 
-        log::debug!("{}|message from queue with sha256 {}: '{}'",
-            milliseconds_since_timestamp(server_started.0),
-            internal.0.sha256,
-            internal.0.contents,
-        );
-        log::info!("{}|{} message with priority of {} proxied, {} queue_requests, {} queued, {} proxy requests, {} proxied, {} in {} queue, request took {} ms",
-            milliseconds_since_timestamp(server_started.0),
-            Size::Bytes(internal.0.size_in_bytes).to_string(Base::Base10, Style::Abbreviated),
-            internal.0.priority,
+// Hash the message contents and check the sha256 sent with the message, if any. Returns
+// the sha256, or why the message was rejected along with the expected sha256.
+fn check_sha256(contents: &str, received: Option<&str>, queue_config: &QueueConfig) -> Result<String, (&'static str, String)> {
+    let sha256 = hash_contents(contents, &queue_config.shared_secret);
+    match received {
+        None if queue_config.require_sha256 => Err(("required sha256 not set", sha256)),
+        Some(received) if received.to_lowercase() != sha256 => Err(("invalid sha256", sha256)),
+        _ => Ok(sha256),
+    }
+}
+
+// Validate a message in a batch the same way as `new`, returning why it is invalid.
+fn prepare_message(
+        name: &str,
+        value: serde_json::Value,
+        queue_config: &QueueConfig,
+        arrived: Timestamp,
+    ) -> Result<(InternalMessage, Option<String>), String> {
+    // Checked before parsing so that the reason is the same as for a single message.
+    if let Some(priority) = value.get("priority").filter(|p| !p.is_null()) {
+        if priority.as_u64().is_none_or(|p| p > Priority::MAX as u64) {
+            return Err("invalid priority".to_string());
+        }
+    }
+    let message: IncomingMessage = serde_json::from_value(value)
+        .map_err(|e| format!("invalid message: {}", e))?;
+
+    let sha256 = check_sha256(&message.contents, message.sha256.as_deref(), queue_config)
+        .map_err(|(reason, _)| reason.to_string())?;
+    let priority = message.priority.unwrap_or(DEFAULT_PRIORITY);
+    let not_before = match (message.deliver_at, message.delay_ms) {
+        (Some(_), Some(_)) => return Err("set either deliver_at or delay_ms, not both".to_string()),
+        (Some(deliver_at), None) => deliver_at as Timestamp,
+        (None, Some(delay_ms)) => arrived + delay_ms as Timestamp,
+        (None, None) => 0,
+    };
+    let expires = match (message.expires_at, message.ttl_ms) {
+        (Some(_), Some(_)) => return Err("set either expires_at or ttl_ms, not both".to_string()),
+        (Some(expires_at), None) => expires_at as Timestamp,
+        (None, Some(ttl_ms)) => arrived + ttl_ms as Timestamp,
+        (None, None) => 0,
+    };
+
+    let internal = InternalMessage {
+        size_in_bytes: std::mem::size_of::<InternalMessage>() + message.contents.capacity() + sha256.capacity() + name.len(),
+        contents: message.contents,
+        sha256,
+        priority,
+        arrived,
+        uuid: Uuid::new_v4(),
+        delivery_attempts: 0,
+        last_error: None,
+        original_priority: priority,
+        sequence: store::next_sequence(),
+        not_before,
+        expires,
+        queue: name.to_string(),
+        reprioritized: Vec::new(),
+    };
+    Ok((internal, message.idempotency_key))
+}
+
+// Accept many messages for the proxy to queue at once. With `atomic=true` either every
+// message is queued or none are, otherwise the valid messages are queued.
+#[post("/batch?<atomic>", format="json", data="<messages>")]
+fn new_batch(
+        atomic: Option<bool>,
+        messages: Json<Vec<serde_json::Value>>,
+        server_started: State<Started>,
+        request_started: RequestTimer,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    queue_batch(DEFAULT_QUEUE, atomic.unwrap_or(false), messages.0, server_started, request_started, queue_config)
+}
+
+// Accept many messages for a named queue at once.
+#[post("/queues/<name>/batch?<atomic>", format="json", data="<messages>")]
+fn new_named_batch(
+        name: String,
+        atomic: Option<bool>,
+        messages: Json<Vec<serde_json::Value>>,
+        server_started: State<Started>,
+        request_started: RequestTimer,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    queue_batch(&name, atomic.unwrap_or(false), messages.0, server_started, request_started, queue_config)
+}
+
+fn queue_batch(
+        name: &str,
+        atomic: bool,
+        messages: Vec<serde_json::Value>,
+        server_started: State<Started>,
+        request_started: RequestTimer,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    // The whole batch counts as one request.
+    let queue_requests = counters.queue_requests.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if !queue_config.memory_limits.contains_key(name) {
+        log::info!("{}|unknown queue '{}', ignoring batch",
+            milliseconds_since_timestamp(server_started.0),
+            name,
+        );
+        return unknown_queue(name, server_started.0, request_started.0);
+    }
+    if shutdown::is_shutting_down() {
+        log::info!("{}|shutting down, ignoring batch",
+            milliseconds_since_timestamp(server_started.0),
+        );
+        return shutting_down(server_started.0, request_started.0);
+    }
+
+    // Everything is checked before anything is queued, so an atomic batch can be
+    // rejected as a whole.
+    let arrived = time_since_epoch().as_millis();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    // Messages may also repeat each other within the batch.
+    let mut batch_sha256s: HashMap<String, Uuid> = HashMap::new();
+    let mut batch_keys: HashMap<String, (Uuid, String)> = HashMap::new();
+    let mut entries = Vec::with_capacity(messages.len());
+    for value in messages {
+        // As with a single message, a repeated idempotency key gets the original
+        // response before the message is checked.
+        let key = value.get("idempotency_key").and_then(|k| k.as_str()).map(|k| k.to_string());
+        if let Some(key) = key.as_ref() {
+            if let Some((uuid, sha256)) = batch_keys.get(key) {
+                entries.push(BatchEntry::Duplicate(*uuid, sha256.clone()));
+                continue;
+            }
+            if let Some(body) = IDEMPOTENCY_KEYS.lock().unwrap().get(&counters, name, key, arrived) {
+                entries.push(BatchEntry::Repeated(body));
+                continue;
+            }
+        }
+        let (message, key) = match prepare_message(name, value, &queue_config, arrived) {
+            Ok(m) => m,
+            Err(reason) => {
+                entries.push(BatchEntry::Invalid(reason));
+                continue;
+            }
+        };
+        if let Some(key) = key.as_ref() {
+            batch_keys.insert(key.clone(), (message.uuid, message.sha256.clone()));
+        }
+        // With deduplication enabled, the same contents sent twice in one batch are a
+        // duplicate too, as they would be if they were sent separately.
+        let mut dedup = DEDUP.lock().unwrap();
+        if dedup.is_enabled() {
+            let duplicate = batch_sha256s.get(&message.sha256).copied()
+                .or_else(|| dedup.find(name, &message.sha256, arrived));
+            if let Some(uuid) = duplicate {
+                entries.push(BatchEntry::Duplicate(uuid, message.sha256));
+                continue;
+            }
+            batch_sha256s.insert(message.sha256.clone(), message.uuid);
+        }
+        drop(dedup);
+        entries.push(BatchEntry::Queue(Box::new(message), key));
+    }
+
+    let invalid = entries.iter().filter(|e| matches!(e, BatchEntry::Invalid(_))).count();
+    let batch_bytes: usize = entries.iter()
+        .map(|e| if let BatchEntry::Queue(m, _) = e { m.size_in_bytes } else { 0 })
+        .sum();
+    let store = queue.get(name).expect("configured queue");
+    // Remembered idempotency keys use the queue's memory too.
+    let idempotency_bytes = IDEMPOTENCY_KEYS.lock().unwrap().bytes(&counters, name, arrived);
+    if atomic && invalid > 0 {
+        log::info!("{}|{} of {} messages in batch are invalid, ignoring batch",
+            milliseconds_since_timestamp(server_started.0),
+            invalid,
+            entries.len(),
+        );
+        return batch_rejected(entries, Status::BadRequest, "invalid messages in batch", server_started.0, request_started.0);
+    }
+    if atomic && !store.has_room(batch_bytes + idempotency_bytes) {
+        log::warn!("{}|queue '{}' is holding {}, unable to store batch of {}",
+            milliseconds_since_timestamp(server_started.0),
+            name,
+            Size::Bytes(store.bytes()),
+            Size::Bytes(batch_bytes),
+        );
+        return batch_rejected(entries, Status::ServiceUnavailable, "insufficient memory", server_started.0, request_started.0);
+    }
+
+    let mut results: Vec<serde_json::Value> = Vec::with_capacity(entries.len());
+    let mut accepted: Vec<InternalMessage> = Vec::new();
+    let mut keys: Vec<(String, serde_json::Value)> = Vec::new();
+    let mut duplicates = 0;
+    for entry in entries {
+        let (message, key) = match entry {
+            BatchEntry::Queue(message, key) => (*message, key),
+            BatchEntry::Duplicate(uuid, sha256) => {
+                duplicates += 1;
+                results.push(json!({
+                    "status": "duplicate",
+                    "code": 200,
+                    "data": {
+                        "uuid": uuid,
+                        "sha256": sha256,
+                        "queue": name,
+                    },
+                }).0);
+                continue;
+            }
+            BatchEntry::Repeated(body) => {
+                duplicates += 1;
+                results.push(body);
+                continue;
+            }
+            BatchEntry::Invalid(reason) => {
+                results.push(json!({
+                    "status": "bad request",
+                    "reason": reason,
+                    "code": 400,
+                }).0);
+                continue;
+            }
+        };
+
+        let store = queue.get(name).expect("configured queue");
+        if !store.has_room(message.size_in_bytes + idempotency_bytes) {
+            results.push(json!({
+                "status": "service unavailable",
+                "reason": "insufficient memory",
+                "code": 503,
+            }).0);
+            continue;
+        }
+        let uuid = message.uuid;
+        let persisted = wal::log_push(&message).map_err(|e| e.to_string()).and_then(|_| {
+            queue.push(message.clone()).map_err(|e| {
+                wal::log_remove(uuid, server_started.0);
+                e.to_string()
+            })
+        });
+        if let Err(e) = persisted {
+            log::error!("{}|failed to store message in batch: {}",
+                milliseconds_since_timestamp(server_started.0),
+                e,
+            );
+            if atomic {
+                // Take back everything already queued from this batch.
+                for message in &accepted {
+                    queue.remove(&message.uuid);
+                    wal::log_remove(message.uuid, server_started.0);
+                }
+                return batch_rejected(Vec::new(), Status::ServiceUnavailable, "unable to persist message", server_started.0, request_started.0);
+            }
+            results.push(json!({
+                "status": "service unavailable",
+                "reason": "unable to persist message",
+                "code": 503,
+            }).0);
+            continue;
+        }
+
+        let result = json!({
+            "status": "accepted",
+            "code": 202,
+            "data": {
+                "uuid": uuid,
+                "sha256": message.sha256,
+                "priority": message.priority,
+                "queue": name,
+            },
+        });
+        if let Some(key) = key {
+            keys.push((key, result.0.clone()));
+        }
+        results.push(result.0);
+        accepted.push(message);
+    }
+
+    // Only count messages and remember keys once the whole batch is queued.
+    let mut idempotency_keys = IDEMPOTENCY_KEYS.lock().unwrap();
+    for (key, result) in keys {
+        idempotency_keys.insert(&counters, name, &key, result, time_since_epoch().as_millis());
+    }
+    drop(idempotency_keys);
+    let mut size_of_batch = 0;
+    for message in &accepted {
+        dedup::queued(message);
+        counters.queue_queued(name, message.size_in_bytes);
+        size_of_batch += message.size_in_bytes;
+    }
+    let queued = counters.queued.fetch_add(accepted.len(), Ordering::Relaxed) + accepted.len();
+    let in_queue = counters.in_queue.fetch_add(accepted.len(), Ordering::Relaxed) + accepted.len();
+    let bytes_allocated_for_queue = counters.bytes.fetch_add(size_of_batch, Ordering::Relaxed) + size_of_batch;
+
+    log::info!("{}|{} of {} messages in batch queued in '{}', {} duplicates, {} queue_requests, {} queued, {} in {} queue, request took {} ms",
+        milliseconds_since_timestamp(server_started.0),
+        accepted.len(),
+        results.len(),
+        name,
+        duplicates,
+        queue_requests,
+        queued,
+        in_queue,
+        Size::Bytes(bytes_allocated_for_queue).to_string(Base::Base10, Style::Abbreviated),
+        milliseconds_since_timestamp(request_started.0),
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "queue_requests": queue_requests,
+            "queued": queued,
+            "in_queue": in_queue,
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+            "request_size": format!("{}", Size::Bytes(size_of_batch)),
+            "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+            "queue": name,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "accepted",
+                "code": 202,
+                "accepted": accepted.len(),
+                "duplicates": duplicates,
+                "rejected": results.len() - accepted.len() - duplicates,
+                "data": results,
+                "debug": debug,
+            }),
+        status: Status::Accepted,
+    }
+}
+
+// Nothing in an atomic batch was queued, the results explain which messages were
+// the problem.
+fn batch_rejected(
+        entries: Vec<BatchEntry>,
+        status: Status,
+        reason: &str,
+        server_started: Duration,
+        request_started: Duration,
+    ) -> QueueApiResponse {
+    let results: Vec<serde_json::Value> = entries.into_iter()
+        .map(|entry| match entry {
+            BatchEntry::Invalid(reason) => json!({
+                "status": "bad request",
+                "reason": reason,
+                "code": 400,
+            }).0,
+            _ => json!({
+                "status": "not queued",
+                "reason": "batch rejected",
+            }).0,
+        })
+        .collect();
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": status.reason.to_lowercase(),
+                "reason": reason,
+                "code": status.code,
+                "data": results,
+                "debug": debug,
+            }),
+        status,
+    }
+}
+
+// Temporary: ultimately the proxy will push this data. With `max` or `max_bytes`,
+// several messages are returned at once. With `wait`, an empty queue is waited on
+// for up to that many seconds.
+#[get("/?<max>&<max_bytes>&<wait>", format = "json")]
+fn get(
+        max: Option<usize>,
+        max_bytes: Option<usize>,
+        wait: Option<u64>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    if let Some(wait) = wait {
+        wait_for_message(DEFAULT_QUEUE, wait, server_started.0);
+    }
+    if max.is_some() || max_bytes.is_some() {
+        return dequeue_batch(DEFAULT_QUEUE, max, max_bytes, request_started, server_started, queue_config);
+    }
+    dequeue(DEFAULT_QUEUE, request_started, server_started, queue_config)
+}
+
+// Get the next message from a named queue.
+#[get("/queues/<name>?<max>&<max_bytes>&<wait>", format = "json")]
+fn get_named(
+        name: String,
+        max: Option<usize>,
+        max_bytes: Option<usize>,
+        wait: Option<u64>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    if let Some(wait) = wait {
+        wait_for_message(&name, wait, server_started.0);
+    }
+    if max.is_some() || max_bytes.is_some() {
+        return dequeue_batch(&name, max, max_bytes, request_started, server_started, queue_config);
+    }
+    dequeue(&name, request_started, server_started, queue_config)
+}
+
+// Wait up to `wait` seconds for a message to be ready in the queue, so consumers don't
+// have to poll an empty queue. Another consumer may still get the message first. If too
+// many requests are already waiting, this returns straight away.
+fn wait_for_message(name: &str, wait: u64, server_started: Duration) {
+    let _waiter = match wakeup::waiter() {
+        Some(w) => w,
+        None => {
+            log::info!("{}|too many requests waiting, not waiting for a message in '{}'",
+                milliseconds_since_timestamp(server_started),
+                name,
+            );
+            return;
+        }
+    };
+    let deadline = Instant::now() + Duration::from_secs(wait.min(MAX_WAIT));
+    loop {
+        let generation = wakeup::generation();
+        match QUEUE.lock().expect("queue lock").get(name) {
+            Some(store) if store.peek().is_none() => (),
+            _ => return,
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        // Scheduled messages become due without being added, so look again every second.
+        wakeup::wait(generation, (deadline - now).min(Duration::from_secs(1)));
+    }
+}
+
+// The message as it is returned to consumers.
+fn message_json(internal: &InternalMessage, receipt: Option<Uuid>, visibility_timeout: usize) -> serde_json::Value {
+    let mut data = json!({
+        "contents": internal.contents,
+        "sha256": internal.sha256,
+        "priority": internal.priority,
+        "original_priority": internal.original_priority,
+        "elapsed": (time_since_epoch().as_millis() - internal.arrived) as usize,
+        "uuid": internal.uuid,
+        "queue": internal.queue,
+    });
+    // Leased messages must be acknowledged with the receipt before the visibility timeout.
+    if let Some(receipt) = receipt {
+        data["receipt"] = json!(receipt).0;
+        data["visibility_timeout"] = json!(visibility_timeout).0;
+    }
+    data.0
+}
+
+// Get up to `max` messages, highest priority first, with no more than `max_bytes` of
+// contents between them. The first message is always returned, however big it is.
+fn dequeue_batch(
+        name: &str,
+        max: Option<usize>,
+        max_bytes: Option<usize>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    let counters = COUNTERS.lock().unwrap();
+    // The whole batch counts as one request.
+    let proxy_requests = counters.proxy_requests.fetch_add(1, Ordering::Relaxed) + 1;
+    let max = max.unwrap_or(usize::MAX).max(1);
+    let max_bytes = max_bytes.unwrap_or(usize::MAX);
+
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let store = match queue.get_mut(name) {
+        Some(s) => s,
+        None => return Some(unknown_queue(name, server_started.0, request_started.0)),
+    };
+    let mut messages: Vec<InternalMessage> = Vec::new();
+    let mut contents_bytes = 0;
+    let now = time_since_epoch().as_millis();
+    while messages.len() < max {
+        // Expired messages are taken regardless of size, so they don't end the batch.
+        let message = if messages.is_empty() {
+            expiry::pop_unexpired(&counters, server_started.0, || store.pop())
+        }
+        else {
+            expiry::pop_unexpired(&counters, server_started.0, || {
+                store.pop_if(&|m| m.is_expired(now) || contents_bytes + m.contents.len() <= max_bytes)
+            })
+        };
+        match message {
+            Some(m) => {
+                contents_bytes += m.contents.len();
+                messages.push(m);
+            }
+            None => break,
+        }
+    }
+    if messages.is_empty() {
+        return None;
+    }
+
+    let count = messages.len();
+    let size_of_batch: usize = messages.iter().map(|m| m.size_in_bytes).sum();
+    let mut data = Vec::with_capacity(count);
+    let proxied;
+    let in_queue;
+    let bytes_allocated_for_queue;
+    if queue_config.lease_mode {
+        // Each message gets its own lease, so they can be acknowledged one at a time.
+        let visibility_timeout = queue_config.visibility_timeout as Timestamp * 1000;
+        let mut leases = LEASES.lock().unwrap();
+        for message in &messages {
+            let receipt = leases.lease(message.clone(), visibility_timeout);
+            data.push(message_json(message, Some(receipt), queue_config.visibility_timeout));
+        }
+        drop(leases);
+        counters.leased.fetch_add(count, Ordering::Relaxed);
+        proxied = counters.proxied.load(Ordering::Relaxed);
+        in_queue = counters.in_queue.load(Ordering::Relaxed);
+        bytes_allocated_for_queue = counters.bytes.load(Ordering::Relaxed);
+    }
+    else {
+        for message in &messages {
+            wal::log_remove(message.uuid, server_started.0);
+            dedup::delivered(&message.uuid);
+            history::finish(message, history::State::Delivered, None);
+            counters.queue_proxied(name, message.size_in_bytes);
+            data.push(message_json(message, None, queue_config.visibility_timeout));
+        }
+
+        // The messages have been sucessfully removed from the queue.
+        proxied = counters.proxied.fetch_add(count, Ordering::Relaxed) + count;
+        in_queue = counters.in_queue.fetch_sub(count, Ordering::Relaxed) - count;
+        bytes_allocated_for_queue = counters.bytes.fetch_sub(size_of_batch, Ordering::Relaxed) - size_of_batch;
+    }
+    drop(queue);
+    let queue_requests = counters.queue_requests.load(Ordering::Relaxed);
+    let queued = counters.queued.load(Ordering::Relaxed);
+
+    log::info!("{}|{} messages ({}) {} from '{}', {} queue_requests, {} queued, {} proxy requests, {} proxied, {} in {} queue, request took {} ms",
+        milliseconds_since_timestamp(server_started.0),
+        count,
+        Size::Bytes(size_of_batch).to_string(Base::Base10, Style::Abbreviated),
+        if queue_config.lease_mode { "leased" } else { "proxied" },
+        name,
+        queue_requests,
+        queued,
+        proxy_requests,
+        proxied,
+        in_queue,
+        Size::Bytes(bytes_allocated_for_queue).to_string(Base::Base10, Style::Abbreviated),
+        milliseconds_since_timestamp(request_started.0),
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "queue_requests": queue_requests,
+            "proxy_requests": proxy_requests,
+            "queued": queued,
+            "proxied": proxied,
+            "in_queue": in_queue,
+            "leased": counters.leased.load(Ordering::Relaxed),
+            "expired": counters.expired.load(Ordering::Relaxed),
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+            "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    Some(QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "count": count,
+                "bytes": contents_bytes,
+                "data": data,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    })
+}
+
+fn dequeue(
+        name: &str,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    let counters = COUNTERS.lock().unwrap();
+    // A GET was routed here, requesting to get something from the queue.
+    let proxy_requests = counters.proxy_requests.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let store = match queue.get_mut(name) {
+        Some(s) => s,
+        None => return Some(unknown_queue(name, server_started.0, request_started.0)),
+    };
+    expiry::pop_unexpired(&counters, server_started.0, || store.pop()).map(|internal| {
+        let proxied;
+        let in_queue;
+        let bytes_allocated_for_queue;
+        let receipt;
+        if queue_config.lease_mode {
+            // The message stays queued, but is hidden until the lease is acknowledged or expires.
+            let visibility_timeout = queue_config.visibility_timeout as Timestamp * 1000;
+            receipt = Some(LEASES.lock().unwrap().lease(internal.clone(), visibility_timeout));
+            counters.leased.fetch_add(1, Ordering::Relaxed);
+            proxied = counters.proxied.load(Ordering::Relaxed);
+            in_queue = counters.in_queue.load(Ordering::Relaxed);
+            bytes_allocated_for_queue = counters.bytes.load(Ordering::Relaxed);
+        }
+        else {
+            wal::log_remove(internal.uuid, server_started.0);
+            dedup::delivered(&internal.uuid);
+            history::finish(&internal, history::State::Delivered, None);
+
+            // A message has been sucessfully removed from the queue.
+            proxied = counters.proxied.fetch_add(1, Ordering::Relaxed) + 1;
+            in_queue = counters.in_queue.fetch_sub(1, Ordering::Relaxed) - 1;
+            bytes_allocated_for_queue = counters.bytes.fetch_sub(internal.size_in_bytes, Ordering::Relaxed) - internal.size_in_bytes;
+            counters.queue_proxied(name, internal.size_in_bytes);
+            receipt = None;
+        }
+        // Retreive other debug statistics
+        let queue_requests = counters.queue_requests.load(Ordering::Relaxed);
+        let queued = counters.queued.load(Ordering::Relaxed);
+
+        log::debug!("{}|message from queue with sha256 {}: '{}'",
+            milliseconds_since_timestamp(server_started.0),
+            internal.sha256,
+            internal.contents,
+        );
+        log::info!("{}|{} message with priority of {} {} from '{}', {} queue_requests, {} queued, {} proxy requests, {} proxied, {} in {} queue, request took {} ms",
+            milliseconds_since_timestamp(server_started.0),
+            Size::Bytes(internal.size_in_bytes).to_string(Base::Base10, Style::Abbreviated),
+            internal.priority,
+            if receipt.is_some() { "leased" } else { "proxied" },
+            name,
             queue_requests,
             queued,
             proxy_requests,
@@ -447,40 +1544,1003 @@ fn get(
             milliseconds_since_timestamp(request_started.0),
         );
 
-        // Message queue returns a tuple, the internal data strucutre and the priority.
         let debug;
         if cfg!(feature = "rqueue-debug") {
             debug = json!({
-                "queue_requests": queue_requests,
-                "proxy_requests": proxy_requests,
-                "queued": queued,
-                "proxied": proxied,
-                "in_queue": in_queue,
+                "queue_requests": queue_requests,
+                "proxy_requests": proxy_requests,
+                "queued": queued,
+                "proxied": proxied,
+                "in_queue": in_queue,
+                "leased": counters.leased.load(Ordering::Relaxed),
+                "expired": counters.expired.load(Ordering::Relaxed),
+                "uptime": milliseconds_since_timestamp(server_started.0),
+                "process_time": milliseconds_since_timestamp(request_started.0),
+                "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+            })
+        }
+        else {
+            debug = json!({})
+        }
+        // Use this to build the JSON response on-the-fly.
+        QueueApiResponse {
+            json: json!({
+                    "status": "ok",
+                    "code": 200,
+                    "data": message_json(&internal, receipt, queue_config.visibility_timeout),
+                    "debug": debug,
+                }),
+            status: Status::Ok,
+        }
+    })
+}
+
+// A queued message as it is shown to operators, only including the contents if asked.
+fn queued_message_json(message: &InternalMessage, contents: bool, redact: bool, now: Timestamp) -> serde_json::Value {
+    let mut data = json!({
+        "uuid": message.uuid,
+        "queue": message.queue,
+        "sha256": message.sha256,
+        "priority": message.priority,
+        "original_priority": message.original_priority,
+        "size_in_bytes": message.size_in_bytes,
+        "age": now.saturating_sub(message.arrived) as usize,
+        "delivery_attempts": message.delivery_attempts,
+    });
+    if contents {
+        data["contents"] = if redact { json!("[redacted]").0 } else { json!(message.contents).0 };
+    }
+    data.0
+}
+
+// Look at the next message to be delivered, without removing it from the queue.
+#[get("/peek?<contents>", format = "json")]
+fn peek(
+        contents: Option<bool>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    peek_queue(DEFAULT_QUEUE, contents.unwrap_or(false), request_started, server_started, queue_config)
+}
+
+// Look at the next message to be delivered from a named queue.
+#[get("/queues/<name>/peek?<contents>", format = "json")]
+fn peek_named(
+        name: String,
+        contents: Option<bool>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    peek_queue(&name, contents.unwrap_or(false), request_started, server_started, queue_config)
+}
+
+fn peek_queue(
+        name: &str,
+        contents: bool,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Option<QueueApiResponse> {
+    let queue = QUEUE.lock().expect("queue lock");
+    let store = match queue.get(name) {
+        Some(s) => s,
+        None => return Some(unknown_queue(name, server_started.0, request_started.0)),
+    };
+    let message = store.peek()?;
+    drop(queue);
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    let now = time_since_epoch().as_millis();
+    Some(QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "data": queued_message_json(&message, contents, queue_config.redact_contents, now),
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    })
+}
+
+// List queued messages in the order they will be delivered, a page at a time.
+#[get("/messages?<queue>&<offset>&<limit>&<min_priority>&<contents>", format = "json")]
+#[allow(clippy::too_many_arguments)]
+fn list_messages(
+        queue: Option<String>,
+        offset: Option<usize>,
+        limit: Option<usize>,
+        min_priority: Option<Priority>,
+        contents: Option<bool>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> QueueApiResponse {
+    let name = queue.unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+    let offset = offset.unwrap_or(0);
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).min(MAX_LIST_LIMIT);
+    let contents = contents.unwrap_or(false);
+
+    let queue = QUEUE.lock().expect("queue lock");
+    let store = match queue.get(&name) {
+        Some(s) => s,
+        None => return unknown_queue(&name, server_started.0, request_started.0),
+    };
+    let messages = store.list(offset, limit, min_priority.unwrap_or(0));
+    let in_queue = store.len();
+    drop(queue);
+
+    let now = time_since_epoch().as_millis();
+    let data: Vec<serde_json::Value> = messages.iter()
+        .map(|message| queued_message_json(message, contents, queue_config.redact_contents, now))
+        .collect();
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "queue": name,
+                "offset": offset,
+                "limit": limit,
+                "count": data.len(),
+                "in_queue": in_queue,
+                "data": data,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Send messages to a consumer as Server-Sent Events as they become available. Each
+// message is leased and must be acknowledged, and at most `credits` messages are
+// waiting to be acknowledged at once.
+#[get("/stream?<credits>")]
+fn stream(
+        credits: Option<usize>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Result<Response<'static>, QueueApiResponse> {
+    subscribe(DEFAULT_QUEUE, credits, request_started, server_started, queue_config)
+}
+
+// Send messages from a named queue as Server-Sent Events.
+#[get("/queues/<name>/stream?<credits>")]
+fn stream_named(
+        name: String,
+        credits: Option<usize>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Result<Response<'static>, QueueApiResponse> {
+    subscribe(&name, credits, request_started, server_started, queue_config)
+}
+
+fn subscribe(
+        name: &str,
+        credits: Option<usize>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+        queue_config: State<QueueConfig>,
+    ) -> Result<Response<'static>, QueueApiResponse> {
+    if !queue_config.memory_limits.contains_key(name) {
+        return Err(unknown_queue(name, server_started.0, request_started.0));
+    }
+    let waiter = match wakeup::waiter() {
+        Some(w) => w,
+        None => {
+            log::warn!("{}|too many requests waiting, refusing to stream from '{}'",
+                milliseconds_since_timestamp(server_started.0),
+                name,
+            );
+            let debug;
+            if cfg!(feature = "rqueue-debug") {
+                debug = json!({
+                    "uptime": milliseconds_since_timestamp(server_started.0),
+                    "process_time": milliseconds_since_timestamp(request_started.0),
+                })
+            }
+            else {
+                debug = json!({})
+            }
+            return Err(QueueApiResponse {
+                json: json!({
+                        "status": "service unavailable",
+                        "reason": "too many waiting requests",
+                        "code": 503,
+                        "debug": debug,
+                    }),
+                status: Status::ServiceUnavailable,
+            });
+        }
+    };
+    let credits = credits.unwrap_or(DEFAULT_STREAM_CREDITS).clamp(1, MAX_STREAM_CREDITS);
+    let subscription = stream::Subscription::new(name, credits, queue_config.visibility_timeout, waiter, server_started.0);
+    Ok(Response::build()
+        .header(ContentType::new("text", "event-stream"))
+        .raw_header("Cache-Control", "no-cache")
+        .chunked_body(subscription, STREAM_CHUNK_SIZE)
+        .finalize())
+}
+
+// Acknowledge a leased message, permanently removing it from the queue.
+#[post("/ack/<receipt>")]
+fn ack(
+        receipt: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    let message = match Uuid::parse_str(&receipt) {
+        Ok(r) => LEASES.lock().unwrap().take(&r),
+        Err(_) => None,
+    };
+    let internal = match message {
+        Some(m) => m,
+        None => return unknown_lease(&receipt, server_started.0, request_started.0),
+    };
+    wal::log_remove(internal.uuid, server_started.0);
+    dedup::delivered(&internal.uuid);
+    history::finish(&internal, history::State::Delivered, None);
+    // A stream may be waiting for credit to send another message.
+    wakeup::notify();
+
+    // A message has been sucessfully removed from the queue.
+    counters.leased.fetch_sub(1, Ordering::Relaxed);
+    let proxied = counters.proxied.fetch_add(1, Ordering::Relaxed) + 1;
+    let in_queue = counters.in_queue.fetch_sub(1, Ordering::Relaxed) - 1;
+    let bytes_allocated_for_queue = counters.bytes.fetch_sub(internal.size_in_bytes, Ordering::Relaxed) - internal.size_in_bytes;
+    counters.queue_proxied(&internal.queue, internal.size_in_bytes);
+
+    log::info!("{}|{} message with priority of {} acknowledged from '{}', {} proxied, {} in {} queue, request took {} ms",
+        milliseconds_since_timestamp(server_started.0),
+        Size::Bytes(internal.size_in_bytes).to_string(Base::Base10, Style::Abbreviated),
+        internal.priority,
+        internal.queue,
+        proxied,
+        in_queue,
+        Size::Bytes(bytes_allocated_for_queue).to_string(Base::Base10, Style::Abbreviated),
+        milliseconds_since_timestamp(request_started.0),
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "proxied": proxied,
+            "in_queue": in_queue,
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+            "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "uuid": internal.uuid,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Reject a leased message, immediately returning it to the queue.
+#[post("/nack/<receipt>")]
+fn nack(
+        receipt: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let message = match Uuid::parse_str(&receipt) {
+        Ok(r) => LEASES.lock().unwrap().take(&r),
+        Err(_) => None,
+    };
+    let mut internal = match message {
+        Some(m) => m,
+        None => return unknown_lease(&receipt, server_started.0, request_started.0),
+    };
+    counters.leased.fetch_sub(1, Ordering::Relaxed);
+    let uuid = internal.uuid;
+    internal.delivery_attempts += 1;
+
+    log::info!("{}|{} message with priority of {} returned to '{}' after {} delivery attempts, request took {} ms",
+        milliseconds_since_timestamp(server_started.0),
+        Size::Bytes(internal.size_in_bytes).to_string(Base::Base10, Style::Abbreviated),
+        internal.priority,
+        internal.queue,
+        internal.delivery_attempts,
+        milliseconds_since_timestamp(request_started.0),
+    );
+    dead_letter::requeue_or_dead_letter(&counters, &mut queue, internal, "rejected by consumer", server_started.0);
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "uuid": uuid,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Respond to requests for a lease that doesn't exist, or has already expired.
+fn unknown_lease(receipt: &str, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+            "receipt": receipt,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "error",
+                "code": 404,
+                "reason": "Unknown or expired lease.",
+                "debug": debug,
+            }),
+        status: Status::NotFound,
+    }
+}
+
+// Respond to requests that would add messages while shutting down.
+fn shutting_down(server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "service unavailable",
+                "reason": "shutting down",
+                "code": 503,
+                "debug": debug,
+            }),
+        status: Status::ServiceUnavailable,
+    }
+}
+
+// Respond to requests for a queue that hasn't been configured.
+fn unknown_queue(name: &str, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+            "queue": name,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "error",
+                "code": 404,
+                "reason": "Unknown queue.",
+                "debug": debug,
+            }),
+        status: Status::NotFound,
+    }
+}
+
+// Summarize a dead letter, optionally including the message contents.
+fn dead_letter_json(letter: &dead_letter::DeadLetter, include_contents: bool) -> serde_json::Value {
+    let mut data = json!({
+        "uuid": letter.message.uuid,
+        "sha256": letter.message.sha256,
+        "priority": letter.message.priority,
+        "original_priority": letter.message.original_priority,
+        "queue": letter.message.queue,
+        "delivery_attempts": letter.message.delivery_attempts,
+        "error": letter.error,
+        "elapsed": (time_since_epoch().as_millis() - letter.died) as usize,
+    });
+    if include_contents {
+        data["contents"] = json!(letter.message.contents).0;
+    }
+    data.0
+}
+
+// Respond to requests for a dead letter that doesn't exist.
+fn unknown_dead_letter(uuid: &str, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+            "uuid": uuid,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "error",
+                "code": 404,
+                "reason": "Unknown dead letter.",
+                "debug": debug,
+            }),
+        status: Status::NotFound,
+    }
+}
+
+fn unknown_message(uuid: &str, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+            "uuid": uuid,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "error",
+                "code": 404,
+                "reason": "Unknown message.",
+                "debug": debug,
+            }),
+        status: Status::NotFound,
+    }
+}
+
+// Find out where a message is: still queued, being delivered, or how it left the queue.
+#[get("/messages/<uuid>", format = "json")]
+fn message_status(
+        uuid: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let parsed = match Uuid::parse_str(&uuid) {
+        Ok(u) => u,
+        Err(_) => return unknown_message(&uuid, server_started.0, request_started.0),
+    };
+    // Hold the queue lock so the message can't move while we look for it.
+    let queue = QUEUE.lock().expect("queue lock");
+    let record = if let Some(message) = queue.find(&parsed) {
+        Some(history::Record::new(&message, history::State::Queued))
+    }
+    else if let Some(message) = LEASES.lock().unwrap().find(&parsed) {
+        Some(history::Record::new(message, history::State::Leased))
+    }
+    else if let Some(letter) = DEAD_LETTERS.lock().unwrap().get(&parsed) {
+        let mut record = history::Record::new(&letter.message, history::State::DeadLettered);
+        record.last_error = Some(letter.error.clone());
+        record.finished = Some(letter.died);
+        Some(record)
+    }
+    else {
+        HISTORY.lock().unwrap().get(&parsed).cloned()
+    };
+    drop(queue);
+    let record = match record {
+        Some(r) => r,
+        None => return unknown_message(&uuid, server_started.0, request_started.0),
+    };
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "data": record,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Remove a queued or leased message so that it is never delivered. If it can't be
+// found, returns what is known about where it went.
+fn cancel_message(
+        counters: &Counters,
+        queue: &mut Queues,
+        uuid: &Uuid,
+        server_started: Duration,
+    ) -> Result<InternalMessage, Option<history::State>> {
+    let message = match queue.remove(uuid) {
+        Some(m) => m,
+        None => match LEASES.lock().unwrap().remove(uuid) {
+            Some(m) => {
+                counters.leased.fetch_sub(1, Ordering::Relaxed);
+                m
+            }
+            None => return Err(HISTORY.lock().unwrap().get(uuid).map(|r| r.state)),
+        },
+    };
+    wal::log_remove(message.uuid, server_started);
+    counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+    counters.bytes.fetch_sub(message.size_in_bytes, Ordering::Relaxed);
+    counters.queue_removed(&message.queue, message.size_in_bytes);
+    dedup::dropped(&message.uuid);
+    history::finish(&message, history::State::Cancelled, None);
+    log::info!("{}|cancelled message {} in '{}'",
+        milliseconds_since_timestamp(server_started),
+        message.uuid,
+        message.queue,
+    );
+    Ok(message)
+}
+
+fn cancelled_response(uuids: Vec<Uuid>, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "count": uuids.len(),
+                "data": uuids,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Cancel a message that hasn't been delivered yet.
+#[delete("/messages/<uuid>")]
+fn cancel(
+        uuid: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let parsed = match Uuid::parse_str(&uuid) {
+        Ok(u) => u,
+        Err(_) => return unknown_message(&uuid, server_started.0, request_started.0),
+    };
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    match cancel_message(&counters, &mut queue, &parsed, server_started.0) {
+        Ok(message) => cancelled_response(vec![message.uuid], server_started.0, request_started.0),
+        Err(Some(state)) => not_queued(&uuid, state, server_started.0, request_started.0),
+        Err(None) => unknown_message(&uuid, server_started.0, request_started.0),
+    }
+}
+
+// The message has already left the queue, or is being delivered right now.
+fn not_queued(uuid: &str, state: history::State, server_started: Duration, request_started: Duration) -> QueueApiResponse {
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started),
+            "process_time": milliseconds_since_timestamp(request_started),
+            "uuid": uuid,
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    let (code, reason, status) = match state {
+        history::State::InFlight | history::State::Leased => (409, "Message is being delivered.", Status::Conflict),
+        _ => (404, "Message has already left the queue.", Status::NotFound),
+    };
+    QueueApiResponse {
+        json: json!({
+                "status": "error",
+                "code": code,
+                "reason": reason,
+                "state": state,
+                "debug": debug,
+            }),
+        status,
+    }
+}
+
+#[derive(Deserialize)]
+struct PriorityChange {
+    priority: Priority,
+}
+
+// Change the priority of a queued message, for example to deliver it next during an
+// incident. The original priority is kept.
+#[patch("/messages/<uuid>", format = "json", data = "<change>")]
+fn reprioritize(
+        uuid: String,
+        change: Json<PriorityChange>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let parsed = match Uuid::parse_str(&uuid) {
+        Ok(u) => u,
+        Err(_) => return unknown_message(&uuid, server_started.0, request_started.0),
+    };
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let previous = match queue.reprioritize(&parsed, change.priority, time_since_epoch().as_millis()) {
+        Some(p) => p,
+        None => {
+            if LEASES.lock().unwrap().find(&parsed).is_some() {
+                return not_queued(&uuid, history::State::Leased, server_started.0, request_started.0);
+            }
+            return match HISTORY.lock().unwrap().get(&parsed).map(|r| r.state) {
+                Some(state) => not_queued(&uuid, state, server_started.0, request_started.0),
+                None => unknown_message(&uuid, server_started.0, request_started.0),
+            };
+        }
+    };
+    let message = queue.find(&parsed).expect("reprioritized message is queued");
+    // Log the change before releasing the queue, so it can't be logged after the
+    // message is removed.
+    wal::log_update(&message, server_started.0);
+    drop(queue);
+    log::warn!("{}|changed priority of message {} in '{}' from {} to {}, originally {}",
+        milliseconds_since_timestamp(server_started.0),
+        message.uuid,
+        message.queue,
+        previous,
+        message.priority,
+        message.original_priority,
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "data": {
+                    "uuid": message.uuid,
+                    "queue": message.queue,
+                    "priority": message.priority,
+                    "previous_priority": previous,
+                    "original_priority": message.original_priority,
+                },
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Cancel every queued message with this sha256, in the default queue unless another
+// is given.
+#[delete("/messages/sha256/<sha256>?<queue>")]
+fn cancel_by_sha256(
+        sha256: String,
+        queue: Option<String>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let name = queue.unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let matches = |message: &InternalMessage| message.sha256 == sha256 && message.queue == name;
+    let mut uuids = queue.select(&matches);
+    uuids.extend(LEASES.lock().unwrap().select(&matches));
+    let cancelled: Vec<Uuid> = uuids.iter()
+        .filter_map(|uuid| cancel_message(&counters, &mut queue, uuid, server_started.0).ok())
+        .map(|message| message.uuid)
+        .collect();
+    if cancelled.is_empty() {
+        return unknown_message(&sha256, server_started.0, request_started.0);
+    }
+    cancelled_response(cancelled, server_started.0, request_started.0)
+}
+
+// Cancel the message that was queued with this idempotency key, in the default queue
+// unless another is given.
+#[delete("/messages/idempotency-key/<key>?<queue>")]
+fn cancel_by_idempotency_key(
+        key: String,
+        queue: Option<String>,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let name = queue.unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let uuid = IDEMPOTENCY_KEYS.lock().unwrap().uuid(&counters, &name, &key, time_since_epoch().as_millis());
+    match uuid.map(|uuid| cancel_message(&counters, &mut queue, &uuid, server_started.0)) {
+        Some(Ok(message)) => cancelled_response(vec![message.uuid], server_started.0, request_started.0),
+        _ => unknown_message(&key, server_started.0, request_started.0),
+    }
+}
+
+// List all messages in the dead-letter queue, oldest first.
+#[get("/dead-letters", format = "json")]
+fn list_dead_letters(
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let dead_letters = DEAD_LETTERS.lock().unwrap();
+    let data: Vec<serde_json::Value> = dead_letters.list().iter()
+        .map(|letter| dead_letter_json(letter, false))
+        .collect();
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "count": dead_letters.len(),
+                "data": data,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Inspect a message in the dead-letter queue, including its contents.
+#[get("/dead-letters/<uuid>", format = "json")]
+fn get_dead_letter(
+        uuid: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let dead_letters = DEAD_LETTERS.lock().unwrap();
+    let letter = match Uuid::parse_str(&uuid).ok().and_then(|u| dead_letters.get(&u)) {
+        Some(l) => l,
+        None => return unknown_dead_letter(&uuid, server_started.0, request_started.0),
+    };
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "data": dead_letter_json(letter, true),
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Return a message from the dead-letter queue to its queue, resetting its delivery attempts.
+#[post("/dead-letters/<uuid>/requeue")]
+fn requeue_dead_letter(
+        uuid: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+    let letter = match Uuid::parse_str(&uuid).ok().and_then(|u| dead_letters.remove(&u)) {
+        Some(l) => l,
+        None => return unknown_dead_letter(&uuid, server_started.0, request_started.0),
+    };
+
+    let mut internal = letter.message.clone();
+    internal.delivery_attempts = 0;
+    if !queue.contains(&internal.queue) {
+        internal.queue = DEFAULT_QUEUE.to_string();
+    }
+    let name = internal.queue.clone();
+    let size_of_request = internal.size_in_bytes;
+    dedup::queued(&internal);
+    let result = wal::log_push(&internal).and_then(|_| queue.push(internal));
+    if let Err(e) = result {
+        log::error!("{}|failed to requeue dead letter {}: {}",
+            milliseconds_since_timestamp(server_started.0),
+            uuid,
+            e,
+        );
+        dedup::dropped(&letter.message.uuid);
+        // The message may have been logged as queued again before it failed.
+        wal::log_dead_letter(&letter, server_started.0);
+        dead_letters.insert(letter);
+        let debug;
+        if cfg!(feature = "rqueue-debug") {
+            debug = json!({
                 "uptime": milliseconds_since_timestamp(server_started.0),
                 "process_time": milliseconds_since_timestamp(request_started.0),
-                "queue_size": format!("{}", Size::Bytes(bytes_allocated_for_queue)),
+                "error": e.to_string(),
             })
         }
         else {
             debug = json!({})
         }
-        // Use this to build the JSON response on-the-fly.
-        QueueApiResponse {
+        return QueueApiResponse {
             json: json!({
-                    "status": "ok",
-                    "code": 200,
-                    "data": {
-                        "contents": internal.0.contents,
-                        "sha256": internal.0.sha256,
-                        "priority": internal.0.priority,
-                        "elapsed": (time_since_epoch().as_millis() - internal.0.arrived) as usize,
-                        "uuid": internal.0.uuid,
-                    },
+                    "status": "service unavailable",
+                    "reason": "unable to persist message",
+                    "code": 503,
                     "debug": debug,
                 }),
-            status: Status::Ok,
-        }
-    })
+            status: Status::ServiceUnavailable,
+        };
+    }
+    counters.dead_letters.fetch_sub(1, Ordering::Relaxed);
+    counters.in_queue.fetch_add(1, Ordering::Relaxed);
+    counters.bytes.fetch_add(size_of_request, Ordering::Relaxed);
+    counters.queue_restored(&name, size_of_request);
+    log::info!("{}|dead letter {} returned to '{}'",
+        milliseconds_since_timestamp(server_started.0),
+        uuid,
+        name,
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "uuid": letter.message.uuid,
+                "queue": name,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Permanently delete a message from the dead-letter queue.
+#[delete("/dead-letters/<uuid>")]
+fn purge_dead_letter(
+        uuid: String,
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+    let letter = match Uuid::parse_str(&uuid).ok().and_then(|u| dead_letters.remove(&u)) {
+        Some(l) => l,
+        None => return unknown_dead_letter(&uuid, server_started.0, request_started.0),
+    };
+    wal::log_remove(letter.message.uuid, server_started.0);
+    counters.dead_letters.fetch_sub(1, Ordering::Relaxed);
+    log::info!("{}|dead letter {} purged",
+        milliseconds_since_timestamp(server_started.0),
+        uuid,
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "purged": 1,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
+}
+
+// Permanently delete all messages from the dead-letter queue.
+#[delete("/dead-letters")]
+fn purge_dead_letters(
+        request_started: RequestTimer,
+        server_started: State<Started>,
+    ) -> QueueApiResponse {
+    let counters = COUNTERS.lock().unwrap();
+    let letters = DEAD_LETTERS.lock().unwrap().drain();
+    for letter in &letters {
+        wal::log_remove(letter.message.uuid, server_started.0);
+    }
+    let purged = letters.len();
+    counters.dead_letters.fetch_sub(purged, Ordering::Relaxed);
+    log::info!("{}|{} dead letters purged",
+        milliseconds_since_timestamp(server_started.0),
+        purged,
+    );
+
+    let debug;
+    if cfg!(feature = "rqueue-debug") {
+        debug = json!({
+            "uptime": milliseconds_since_timestamp(server_started.0),
+            "process_time": milliseconds_since_timestamp(request_started.0),
+        })
+    }
+    else {
+        debug = json!({})
+    }
+    QueueApiResponse {
+        json: json!({
+                "status": "ok",
+                "code": 200,
+                "purged": purged,
+                "debug": debug,
+            }),
+        status: Status::Ok,
+    }
 }
 
 #[catch(404)]
@@ -495,16 +2555,129 @@ fn not_found() -> QueueApiResponse {
     }
 }
 
+// The default queue is stored directly in `path`, named queues in a subdirectory.
+fn queue_path(path: &str, name: &str) -> String {
+    if name == DEFAULT_QUEUE {
+        path.to_string()
+    }
+    else {
+        Path::new(path).join(name).to_string_lossy().into_owned()
+    }
+}
+
+// Open the store for a queue, using the configured backend.
+fn open_store(config: &rocket::Config, backend: &str, name: &str, memory_limit: usize) -> Box<dyn QueueStore> {
+    match backend {
+        "memory" => {
+            let overflow = match config.get_str("queue_overflow_path") {
+                Ok(path) => {
+                    let path = queue_path(path, name);
+                    let overflow_limit = match config.get_int("queue_overflow_limit_in_bytes") {
+                        Ok(n) => n as usize,
+                        Err(_) => DEFAULT_MAXIMUM_DISK_SIZE,
+                    };
+                    match DiskStore::create(&path, overflow_limit) {
+                        Ok(d) => {
+                            log::info!("Queue '{}' overflow: {}, limit {}", name, path, Size::Bytes(overflow_limit));
+                            Some(d)
+                        }
+                        Err(e) => {
+                            log::error!("Fatal error: unable to open queue overflow '{}': {}", path, e);
+                            process::exit(1);
+                        }
+                    }
+                }
+                Err(_) => {
+                    log::info!("Queue '{}' overflow: disabled", name);
+                    None
+                }
+            };
+            Box::new(MemoryStore::new(memory_limit, overflow))
+        }
+        "disk" => {
+            let path = match config.get_str("queue_disk_path") {
+                Ok(n) => queue_path(n, name),
+                Err(_) => {
+                    log::error!("Fatal error: 'queue_disk_path' is required for the disk backend.");
+                    process::exit(1);
+                }
+            };
+            let disk_limit = match config.get_int("queue_disk_limit_in_bytes") {
+                Ok(n) => n as usize,
+                Err(_) => DEFAULT_MAXIMUM_DISK_SIZE,
+            };
+            match DiskStore::open(&path, disk_limit) {
+                Ok(d) => {
+                    log::info!("Queue '{}' disk path: {}, limit {}", name, path, Size::Bytes(disk_limit));
+                    Box::new(d)
+                }
+                Err(e) => {
+                    log::error!("Fatal error: unable to open queue disk path '{}': {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        _ => {
+            log::error!("Fatal error: unknown queue_backend '{}', expected 'memory' or 'disk'.", backend);
+            process::exit(1);
+        }
+    }
+}
+
 fn rocket(server_started: Duration) -> rocket::Rocket {
     rocket::ignite()
         .manage(Started(server_started))
         .attach(AdHoc::on_attach("Custom Configuration", |rocket| {
             let mut queue_config: QueueConfig = QueueConfig::default();
-            queue_config.memory_limit = match rocket.config().get_int("queue_memory_limit_in_bytes") {
+            let default_memory_limit = match rocket.config().get_int("queue_memory_limit_in_bytes") {
                 Ok(n) => n as usize,
                 Err(_) => DEFAULT_MAXIMUM_QUEUE_SIZE,
             };
-            log::info!("Queue memory limit: {}", Size::Bytes(queue_config.memory_limit));
+            log::info!("Queue memory limit: {}", Size::Bytes(default_memory_limit));
+            queue_config.memory_limits.insert(DEFAULT_QUEUE.to_string(), default_memory_limit);
+
+            // Named queues are configured in a `queues` table, each optionally with its own memory limit.
+            if let Ok(table) = rocket.config().get_table("queues") {
+                for (name, settings) in table {
+                    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                        log::error!("Fatal error: invalid queue name '{}', only letters, numbers, '-' and '_' are allowed.", name);
+                        process::exit(1);
+                    }
+                    let memory_limit = match settings.get("memory_limit_in_bytes").and_then(|v| v.as_integer()) {
+                        Some(n) => n as usize,
+                        None => default_memory_limit,
+                    };
+                    log::info!("Queue '{}' memory limit: {}", name, Size::Bytes(memory_limit));
+                    queue_config.memory_limits.insert(name.to_string(), memory_limit);
+                }
+            }
+
+            let backend = match rocket.config().get_str("queue_backend") {
+                Ok(n) => n.to_string(),
+                Err(_) => "memory".to_string(),
+            };
+            log::info!("Queue backend: {}", backend);
+            let mut queues = Queues::new();
+            for (name, memory_limit) in &queue_config.memory_limits {
+                let store = open_store(rocket.config(), &backend, name, *memory_limit);
+                queues.insert(name, Box::new(ScheduledStore::new(store)));
+            }
+            {
+                // The disk backend may already hold messages from a previous run.
+                let mut counters = COUNTERS.lock().unwrap();
+                let mut queue = QUEUE.lock().expect("queue lock");
+                counters.queues.clear();
+                for name in queue_config.memory_limits.keys() {
+                    let store = queues.get(name).unwrap();
+                    let queue_counters = QueueCounters::default();
+                    queue_counters.in_queue.store(store.len(), Ordering::Relaxed);
+                    queue_counters.bytes.store(store.bytes(), Ordering::Relaxed);
+                    counters.queues.insert(name.to_string(), queue_counters);
+                }
+                counters.in_queue.store(queues.len(), Ordering::Relaxed);
+                counters.bytes.store(queues.bytes(), Ordering::Relaxed);
+                *queue = queues;
+            }
 
             queue_config.require_sha256 = match rocket.config().get_bool("require_sha256") {
                 Ok(n) => n,
@@ -518,6 +2691,124 @@ fn rocket(server_started: Duration) -> rocket::Rocket {
             };
             log::info!("Shared secret: {}", queue_config.shared_secret);
 
+            queue_config.lease_mode = match rocket.config().get_bool("lease_mode") {
+                Ok(n) => n,
+                Err(_) => false,
+            };
+            log::info!("Lease mode: {}", queue_config.lease_mode);
+            queue_config.visibility_timeout = match rocket.config().get_int("lease_visibility_timeout") {
+                Ok(n) => {
+                    if n > 0 {
+                        n as usize
+                    }
+                    else {
+                        DEFAULT_VISIBILITY_TIMEOUT
+                    }
+                }
+                Err(_) => DEFAULT_VISIBILITY_TIMEOUT,
+            };
+            log::info!("Lease visibility timeout: {} s", queue_config.visibility_timeout);
+
+            // Long polls and streams each hold a worker while they wait, so always leave
+            // at least one worker for everything else.
+            let workers = rocket.config().workers as usize;
+            let max_waiters = match rocket.config().get_int("max_waiting_requests") {
+                Ok(n) if n >= 0 => {
+                    if n as usize >= workers {
+                        log::warn!("max_waiting_requests of {} must be less than the {} workers, using {}", n, workers, workers.saturating_sub(1));
+                    }
+                    (n as usize).min(workers.saturating_sub(1))
+                }
+                _ => workers / 2,
+            };
+            wakeup::set_max_waiters(max_waiters);
+            log::info!("Max waiting requests: {} of {} workers", max_waiters, workers);
+            queue_config.redact_contents = match rocket.config().get_bool("redact_contents") {
+                Ok(n) => n,
+                Err(_) => false,
+            };
+            log::info!("Redact contents: {}", queue_config.redact_contents);
+
+            {
+                let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+                *dead_letters = dead_letter::DeadLetters::default();
+                dead_letters.max_delivery_attempts = match rocket.config().get_int("max_delivery_attempts") {
+                    Ok(n) if n > 0 => n as usize,
+                    _ => 0,
+                };
+                if dead_letters.max_delivery_attempts > 0 {
+                    log::info!("Max delivery attempts: {}", dead_letters.max_delivery_attempts);
+                }
+                else {
+                    log::info!("Max delivery attempts: unlimited");
+                }
+                dead_letters.limit = match rocket.config().get_int("dead_letter_limit") {
+                    Ok(n) if n > 0 => n as usize,
+                    _ => DEFAULT_DEAD_LETTER_LIMIT,
+                };
+                log::info!("Dead-letter queue limit: {}", dead_letters.limit);
+            }
+
+            {
+                let mut retry_config = RETRY_CONFIG.lock().unwrap();
+                retry_config.base = match rocket.config().get_int("retry_backoff_base_ms") {
+                    Ok(n) if n > 0 => n as Timestamp,
+                    _ => retry::DEFAULT_BACKOFF_BASE,
+                };
+                retry_config.max = match rocket.config().get_int("retry_backoff_max_ms") {
+                    Ok(n) if n > 0 => n as Timestamp,
+                    _ => retry::DEFAULT_BACKOFF_MAX,
+                };
+                retry_config.jitter = match rocket.config().get_float("retry_backoff_jitter") {
+                    Ok(n) if (0.0..=1.0).contains(&n) => n,
+                    Ok(n) => {
+                        log::error!("Fatal error: retry_backoff_jitter must be between 0.0 and 1.0, not {}.", n);
+                        process::exit(1);
+                    }
+                    Err(_) => retry::DEFAULT_BACKOFF_JITTER,
+                };
+                log::info!("Retry backoff: {} ms doubling up to {} ms, {} jitter",
+                    retry_config.base,
+                    retry_config.max,
+                    retry_config.jitter,
+                );
+            }
+
+            match rocket.config().get_str("queue_wal_path") {
+                Ok(path) => {
+                    match wal::WriteAheadLog::open(path) {
+                        Ok(mut w) => {
+                            w.compaction_size = match rocket.config().get_int("queue_wal_compaction_size_in_bytes") {
+                                Ok(n) if n > 0 => n as u64,
+                                _ => wal::DEFAULT_COMPACTION_SIZE,
+                            };
+                            log::info!("Write-ahead log: {}, compacted past {}", path, Size::Bytes(w.compaction_size));
+                            *WAL.lock().unwrap() = w;
+                        }
+                        Err(e) => {
+                            log::error!("Fatal error: unable to open write-ahead log '{}': {}", path, e);
+                            process::exit(1);
+                        }
+                    }
+                }
+                // The disk backend removes messages as they are handed out, and keeps
+                // scheduled messages in memory, so only the log saves those from a crash.
+                Err(_) if backend == "disk" => log::warn!("Write-ahead log: disabled, leased, scheduled and in-flight messages won't survive a crash"),
+                Err(_) => log::info!("Write-ahead log: disabled"),
+            };
+
+            let snapshot_path = match rocket.config().get_str("queue_snapshot_path") {
+                Ok(n) => n.to_string(),
+                Err(_) => "".to_string(),
+            };
+            if snapshot_path.is_empty() {
+                log::info!("Shutdown snapshot: disabled");
+            }
+            else {
+                log::info!("Shutdown snapshot: {}", snapshot_path);
+            }
+            *SNAPSHOT_PATH.lock().unwrap() = snapshot_path;
+
             if cfg!(feature = "rqueue-proxy") {
                 let mut proxy_config = PROXY_CONFIG.lock().unwrap();
                 proxy_config.delay = match rocket.config().get_int("proxy_delay") {
@@ -532,14 +2823,155 @@ fn rocket(server_started: Duration) -> rocket::Rocket {
                     Err(_) => DEFAULT_DELAY,
                 };
                 log::info!("Proxy delay: {} s", proxy_config.delay);
-                proxy_config.server = match rocket.config().get_str("notification_server") {
-                    Ok(n) => n.to_string(),
-                    Err(_) => {
+                // Either a single `notification_server`, or a list of `notification_servers`
+                // each optionally with a weight.
+                let mut upstreams: Vec<upstream::Upstream> = Vec::new();
+                match (rocket.config().get_str("notification_server"), rocket.config().get_slice("notification_servers")) {
+                    (Ok(n), Err(_)) => upstreams.push(upstream::Upstream::new(n, 1)),
+                    (Err(_), Ok(servers)) => {
+                        for server in servers {
+                            let (url, weight) = match (server.as_str(), server.get("url").and_then(|v| v.as_str())) {
+                                (Some(url), _) => (url, 1),
+                                (None, Some(url)) => (url, server.get("weight").and_then(|v| v.as_integer()).unwrap_or(1)),
+                                (None, None) => {
+                                    log::error!("Fatal error: each of 'notification_servers' must be a url, or a table with a 'url'.");
+                                    process::exit(1);
+                                }
+                            };
+                            if weight < 1 {
+                                log::error!("Fatal error: notification server '{}' has weight {}, it must be at least 1.", url, weight);
+                                process::exit(1);
+                            }
+                            if upstreams.iter().any(|u| u.url == url) {
+                                log::error!("Fatal error: notification server '{}' is listed more than once.", url);
+                                process::exit(1);
+                            }
+                            upstreams.push(upstream::Upstream::new(url, weight as usize));
+                        }
+                        if upstreams.is_empty() {
+                            log::error!("Fatal error: 'notification_servers' is empty.");
+                            process::exit(1);
+                        }
+                    }
+                    (Ok(_), Ok(_)) => {
+                        log::error!("Fatal error: set either 'notification_server' or 'notification_servers' in Rocket.toml, not both.");
+                        process::exit(1);
+                    }
+                    (Err(_), Err(_)) => {
                         log::error!("Fatal error: 'notification_server' was not found in Rocket.toml.");
                         process::exit(1);
                     }
                 };
-                log::info!("Notification server: {}", proxy_config.server);
+                for upstream in &upstreams {
+                    log::info!("Notification server: {} (weight {})", upstream.url, upstream.weight);
+                }
+                let strategy = match rocket.config().get_str("proxy_strategy") {
+                    Ok(n) => match upstream::Strategy::parse(n) {
+                        Some(strategy) => strategy,
+                        None => {
+                            log::error!("Fatal error: unknown proxy_strategy '{}', expected 'round-robin', 'weighted' or 'failover'.", n);
+                            process::exit(1);
+                        }
+                    },
+                    Err(_) => upstream::Strategy::RoundRobin,
+                };
+                log::info!("Proxy strategy: {}", strategy.name());
+                proxy_config.upstreams = upstream::Upstreams::new(strategy, upstreams);
+                proxy_config.upstreams.failure_limit = match rocket.config().get_int("proxy_failure_limit") {
+                    Ok(n) if n >= 0 => n as usize,
+                    _ => upstream::DEFAULT_FAILURE_LIMIT,
+                };
+                proxy_config.upstreams.ejection_time = match rocket.config().get_int("proxy_ejection_time") {
+                    Ok(n) if n > 0 => n as Timestamp * 1000,
+                    _ => upstream::DEFAULT_EJECTION_TIME,
+                };
+                if proxy_config.upstreams.failure_limit > 0 {
+                    log::info!("Upstream ejection: after {} failures, for {} s",
+                        proxy_config.upstreams.failure_limit,
+                        proxy_config.upstreams.ejection_time / 1000,
+                    );
+                }
+                else {
+                    log::info!("Upstream ejection: disabled");
+                }
+                proxy_config.dead_letter_client_errors = match rocket.config().get_str("proxy_client_error_action") {
+                    Ok("dead-letter") | Err(_) => true,
+                    Ok("drop") => false,
+                    Ok(n) => {
+                        log::error!("Fatal error: unknown proxy_client_error_action '{}', expected 'dead-letter' or 'drop'.", n);
+                        process::exit(1);
+                    }
+                };
+                log::info!("Dead-letter client errors: {}", proxy_config.dead_letter_client_errors);
+            }
+
+            let dead_letter_expired = match rocket.config().get_str("expired_message_action") {
+                Ok("drop") | Err(_) => false,
+                Ok("dead-letter") => true,
+                Ok(n) => {
+                    log::error!("Fatal error: unknown expired_message_action '{}', expected 'drop' or 'dead-letter'.", n);
+                    process::exit(1);
+                }
+            };
+            expiry::DEAD_LETTER_EXPIRED.store(dead_letter_expired, Ordering::Relaxed);
+            log::info!("Dead-letter expired messages: {}", dead_letter_expired);
+
+            {
+                let mut dedup = DEDUP.lock().unwrap();
+                *dedup = dedup::Dedup::default();
+                dedup.window = match rocket.config().get_int("dedup_window") {
+                    Ok(n) if n > 0 => n as Timestamp * 1000,
+                    _ => 0,
+                };
+                if dedup.is_enabled() {
+                    log::info!("Deduplication window: {} s", dedup.window / 1000);
+                }
+                else {
+                    log::info!("Deduplication: disabled");
+                }
+            }
+
+            {
+                let mut idempotency_keys = IDEMPOTENCY_KEYS.lock().unwrap();
+                *idempotency_keys = idempotency::IdempotencyKeys::default();
+                let retention = match rocket.config().get_int("idempotency_key_retention") {
+                    Ok(n) if n > 0 => n as usize,
+                    _ => idempotency::DEFAULT_IDEMPOTENCY_KEY_RETENTION,
+                };
+                idempotency_keys.retention = retention as Timestamp * 1000;
+                log::info!("Idempotency key retention: {} s", retention);
+            }
+
+            {
+                let mut history = HISTORY.lock().unwrap();
+                *history = history::History::default();
+                history.limit = match rocket.config().get_int("message_history_limit") {
+                    Ok(n) if n >= 0 => n as usize,
+                    _ => history::DEFAULT_HISTORY_LIMIT,
+                };
+                log::info!("Message history limit: {}", history.limit);
+            }
+
+            {
+                let mut aging = AGING_CONFIG.lock().unwrap();
+                aging.interval = match rocket.config().get_int("priority_aging_interval") {
+                    Ok(n) if n > 0 => n as Timestamp * 1000,
+                    _ => 0,
+                };
+                aging.ceiling = match rocket.config().get_int("priority_aging_ceiling") {
+                    Ok(n) if (0..=Priority::MAX as i64).contains(&n) => n as Priority,
+                    Ok(n) => {
+                        log::error!("Fatal error: priority_aging_ceiling must be between 0 and {}, not {}.", Priority::MAX, n);
+                        process::exit(1);
+                    }
+                    Err(_) => Priority::MAX,
+                };
+                if aging.is_enabled() {
+                    log::info!("Priority aging: +1 every {} s, up to {}", aging.interval / 1000, aging.ceiling);
+                }
+                else {
+                    log::info!("Priority aging: disabled");
+                }
             }
 
             if cfg!(feature = "rqueue-notify") {
@@ -599,12 +3031,30 @@ fn rocket(server_started: Duration) -> rocket::Rocket {
             req.local_cache(|| RequestTimer(time_since_epoch()));
         }))
         .register(catchers![not_found])
-        .mount("/", routes![new, get])
+        .mount("/", routes![new, get, new_named, get_named, new_batch, new_named_batch, ack, nack,
+            list_dead_letters, get_dead_letter, requeue_dead_letter, purge_dead_letter, purge_dead_letters, message_status,
+            cancel, cancel_by_sha256, cancel_by_idempotency_key, reprioritize,
+            peek, peek_named, list_messages, stream, stream_named])
 }
 
 fn main() {
     let server_started = time_since_epoch();
 
+    // Configuration is loaded while building the server, then any messages
+    // still in the write-ahead log or the shutdown snapshot are restored before
+    // anything reads the queue.
+    let server = rocket(server_started);
+    wal::restore(server_started);
+    shutdown::restore(server_started);
+    // Restored messages have the priority they were saved with, before any aging.
+    aging::age(server_started);
+
+    // Save the queue before exiting on SIGINT or SIGTERM.
+    if let Err(e) = ctrlc::set_handler(move || shutdown::shutdown(server_started)) {
+        log::error!("Fatal error: unable to install signal handler: {}", e);
+        process::exit(1);
+    }
+
     if cfg!(feature = "rqueue-proxy") {
         // Proxy thread reads queue and pushes notifications upstream.
         thread::spawn(move || {
@@ -623,6 +3073,26 @@ fn main() {
         });
     }
 
+    // Lease thread returns messages to the queue if their lease expires.
+    thread::spawn(move || {
+        lease::lease_loop(server_started);
+    });
+
+    // Aging thread raises the priority of messages that have been waiting.
+    thread::spawn(move || {
+        aging::aging_loop(server_started);
+    });
+
+    // Compaction thread stops the write-ahead log from growing forever.
+    thread::spawn(move || {
+        wal::compaction_loop(server_started);
+    });
+
+    // Expiry thread removes messages that weren't delivered in time.
+    thread::spawn(move || {
+        expiry::expiry_loop(server_started);
+    });
+
     // REST server collects notifications in the queue.
-    rocket(server_started).launch();
+    server.launch();
 }
@@ -1,5 +1,6 @@
 use std::thread;
 use std::time::Duration;
+use std::sync::atomic::Ordering;
 
 use lettre_email::{Email};
 use lettre::smtp::authentication::{Credentials, Mechanism};
@@ -7,90 +8,46 @@ use lettre::{Transport, SmtpClient};
 use lettre::smtp::extension::ClientId;
 use lettre::smtp::ConnectionReuseParameters;
 
-use crate::{NOTIFY_CONFIG, DEFAULT_DELAY, COUNTERS, QUEUE, milliseconds_since_timestamp, InternalMessage};
+use crate::{DELIVERY, NOTIFY_CONFIG, DEFAULT_DELAY, COUNTERS, QUEUE, milliseconds_since_timestamp, InternalMessage, wal, dedup, history, dead_letter, expiry, retry, wakeup};
 
 pub fn notify_loop(server_started: Duration) {
     let mut sleep_time = DEFAULT_DELAY;
+    // When the queue is empty, wait for a message to be added instead of sleeping.
+    let mut idle = false;
+    let mut generation = 0;
     loop {
         log::debug!("{}|top of notify loop", milliseconds_since_timestamp(server_started));
-        thread::sleep(Duration::from_secs(sleep_time as u64));
+        if idle {
+            // Scheduled messages become due without being added, so still check
+            // again after the delay.
+            wakeup::wait(generation, Duration::from_secs(sleep_time as u64));
+        }
+        else {
+            thread::sleep(Duration::from_secs(sleep_time as u64));
+        }
+
+        // Don't start a delivery while shutting down, and make shutdown wait for this one.
+        let _delivery = DELIVERY.lock().unwrap();
 
-        let queue_contents;
-        // We preserve a copy of the message in case there's an error, as then we'll
-        // return it to the queue.
-        let mut internal_message: InternalMessage = InternalMessage::default();
+        // The message being delivered, which is returned to the queue if there's an error.
+        let queue_contents: Option<InternalMessage>;
         {
-            // We don't use counters here, but we have to grab locks in order to prevent a race
+            // Counters are only needed for expired messages, but are locked first to keep the lock order.
+            let counters = COUNTERS.lock().unwrap();
             let mut queue = QUEUE.lock().expect("queue lock");
-            queue_contents = queue.pop().map(|internal| {
-                internal_message.size_in_bytes = internal.0.size_in_bytes;
-                internal_message.contents = internal.0.contents.clone();
-                internal_message.sha256 = internal.0.sha256.clone();
-                internal_message.priority = internal.0.priority;
-                internal_message.arrived = internal.0.arrived;
-                internal_message.uuid = internal.0.uuid.clone();
-                internal_message.original_priority = internal.0.original_priority;
-                internal_message.delivery_attempts = internal.0.delivery_attempts + 1;
+            generation = wakeup::generation();
+            queue_contents = expiry::pop_unexpired(&counters, server_started, || queue.pop()).map(|mut internal| {
+                internal.delivery_attempts += 1;
+                history::start(&internal);
+                internal
             });
         }
 
+        idle = queue_contents.is_none();
         // Send notifications
-        if queue_contents != None {
+        if let Some(internal_message) = queue_contents {
             sleep_time = 0;
-            log::debug!("{}|message from queue with sha256 {}: '{}'",
-                milliseconds_since_timestamp(server_started),
-                &internal_message.sha256,
-                &internal_message.contents,
-            );
-
-            let notification: rqpush::OutboundNotification = match serde_json::from_str(&internal_message.contents) {
-                Ok(m) => m,
-                Err(_) => {
-                    // @TODO: generate a useful error
-                    rqpush::OutboundNotification::default()
-                }
-            };
-
-            let notify_config = NOTIFY_CONFIG.lock().unwrap();
-            let email = Email::builder()
-                .from((&notify_config.mail_from_address.to_string(), &notify_config.mail_from_name.to_string()))
-                .to((&notify_config.mail_to_address.to_string(), &notify_config.mail_to_name.to_string()))
-                .subject(&notification.title)
-                .alternative(&notification.short_html, &notification.short_text)
-                .build()
-                .expect("failed to create email");
-            
-            let smtp_user = &notify_config.smtp_user;
-            let smtp_password = &notify_config.smtp_password;
-            match SmtpClient::new_simple(&notify_config.smtp_server.to_string()) {
-                Ok(m) => {
-                    let mut mailer = m
-                        // Set the name sent during EHLO/HELO, default is `localhost`
-                        .hello_name(ClientId::Domain("localhost".to_string()))
-                        // Add credentials for authentication
-                        .credentials(Credentials::new(smtp_user.to_string(), smtp_password.to_string()))
-                        // Enable SMTPUTF8 if the server supports it
-                        .smtp_utf8(true)
-                        // Configure expected authentication mechanism
-                        .authentication_mechanism(Mechanism::Plain)
-                        // Enable connection reuse
-                        .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
-                        .transport();
-                    let result = mailer.send(email.into());
-                    log::debug!("result {:?}", result);
-                }
-                Err(e) => {
-                    log::warn!("failed to initialize SmtpClient: {}", e);
-                    // sleep a while and try again, something went wrong
-                    sleep_time = notify_config.delay;
-
-                    let priority = internal_message.priority;
-                    // We don't need counters here, but we have to grab locks in order to avoid a race
-                    let _counters = COUNTERS.lock().unwrap();
-                    let mut queue = QUEUE.lock().expect("queue lock");
-                    queue.push(internal_message, priority);
-                } 
-            }
+            notify(internal_message, server_started);
         }
         else {
             // If the queue is empty, sleep longer.
@@ -98,4 +55,91 @@ pub fn notify_loop(server_started: Duration) {
             sleep_time = notify_config.delay;
         }
     }
-}
\ No newline at end of file
+}
+
+// Send a message from the queue as an email. If it can't be sent, it is returned to the
+// queue to be retried after a backoff, or dead-lettered once out of delivery attempts.
+pub(crate) fn notify(mut internal_message: InternalMessage, server_started: Duration) {
+    log::debug!("{}|message from queue with sha256 {}: '{}'",
+        milliseconds_since_timestamp(server_started),
+        &internal_message.sha256,
+        &internal_message.contents,
+    );
+
+    let notification: rqpush::OutboundNotification = match serde_json::from_str(&internal_message.contents) {
+        Ok(m) => m,
+        Err(_) => {
+            // @TODO: generate a useful error
+            rqpush::OutboundNotification::default()
+        }
+    };
+
+    let result = {
+        let notify_config = NOTIFY_CONFIG.lock().unwrap();
+        let email = Email::builder()
+            .from((&notify_config.mail_from_address.to_string(), &notify_config.mail_from_name.to_string()))
+            .to((&notify_config.mail_to_address.to_string(), &notify_config.mail_to_name.to_string()))
+            .subject(&notification.title)
+            .alternative(&notification.short_html, &notification.short_text)
+            .build()
+            .expect("failed to create email");
+
+        let smtp_user = &notify_config.smtp_user;
+        let smtp_password = &notify_config.smtp_password;
+        match SmtpClient::new_simple(&notify_config.smtp_server.to_string()) {
+            Ok(m) => {
+                let mut mailer = m
+                    // Set the name sent during EHLO/HELO, default is `localhost`
+                    .hello_name(ClientId::Domain("localhost".to_string()))
+                    // Add credentials for authentication
+                    .credentials(Credentials::new(smtp_user.to_string(), smtp_password.to_string()))
+                    // Enable SMTPUTF8 if the server supports it
+                    .smtp_utf8(true)
+                    // Configure expected authentication mechanism
+                    .authentication_mechanism(Mechanism::Plain)
+                    // Enable connection reuse
+                    .connection_reuse(ConnectionReuseParameters::ReuseUnlimited)
+                    .transport();
+                let result = mailer.send(email.into());
+                log::debug!("result {:?}", result);
+                result.map(|_| ()).map_err(|e| format!("failed to send email: {}", e))
+            }
+            Err(e) => Err(format!("failed to initialize SmtpClient: {}", e)),
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            wal::log_remove(internal_message.uuid, server_started);
+            dedup::delivered(&internal_message.uuid);
+            history::finish(&internal_message, history::State::Delivered, None);
+            let counters = COUNTERS.lock().unwrap();
+            // A message has been sucessfully removed from the queue.
+            let proxied = counters.proxied.fetch_add(1, Ordering::Relaxed) + 1;
+            let in_queue = counters.in_queue.fetch_sub(1, Ordering::Relaxed) - 1;
+            counters.bytes.fetch_sub(internal_message.size_in_bytes, Ordering::Relaxed);
+            counters.queue_proxied(&internal_message.queue, internal_message.size_in_bytes);
+            log::info!("{}|message with priority of {} sent as an email from '{}', {} proxied, {} in queue",
+                milliseconds_since_timestamp(server_started),
+                internal_message.priority,
+                internal_message.queue,
+                proxied,
+                in_queue,
+            );
+        }
+        Err(error) => {
+            // Hold this message back for a while, something went wrong.
+            let backoff = retry::schedule_retry(&mut internal_message);
+            log::warn!("{}|notify failure {}, retrying in {} ms: {}",
+                milliseconds_since_timestamp(server_started),
+                internal_message.delivery_attempts,
+                backoff,
+                error,
+            );
+
+            let counters = COUNTERS.lock().unwrap();
+            let mut queue = QUEUE.lock().expect("queue lock");
+            dead_letter::requeue_or_dead_letter(&counters, &mut queue, internal_message, &error, server_started);
+        }
+    }
+}
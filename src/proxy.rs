@@ -1,49 +1,76 @@
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::sync::atomic::Ordering;
 use serde_json::json;
+use reqwest::StatusCode;
+use reqwest::header::RETRY_AFTER;
 
-use crate::{COUNTERS, QUEUE, PROXY_CONFIG, DEFAULT_DELAY, milliseconds_since_timestamp, InternalMessage};
+use crate::{DELIVERY, COUNTERS, QUEUE, PROXY_CONFIG, DEFAULT_DELAY, milliseconds_since_timestamp, time_since_epoch, InternalMessage, Timestamp, wal, dedup, history, dead_letter, expiry, retry, wakeup};
 
 use size::{Base, Size, Style};
 
 
 pub fn proxy_loop(server_started: Duration) {
     let mut sleep_time = DEFAULT_DELAY;
+    // When the queue is empty, wait for a message to be added instead of sleeping.
+    let mut idle = false;
+    let mut generation = 0;
     loop {
         log::debug!("{}|top of proxy loop", milliseconds_since_timestamp(server_started));
-        thread::sleep(Duration::from_secs(sleep_time as u64));
+        if idle {
+            // Scheduled messages become due without being added, so still check
+            // again after the delay.
+            wakeup::wait(generation, Duration::from_secs(sleep_time as u64));
+        }
+        else {
+            thread::sleep(Duration::from_secs(sleep_time as u64));
+        }
+
+        // Don't start a delivery while shutting down, and make shutdown wait for this one.
+        let _delivery = DELIVERY.lock().unwrap();
 
-        let queue_contents;
-        // We preserve a copy of the message in case there's an error, as then we'll
-        // return it to the queue.
-        let mut internal_message: InternalMessage = InternalMessage::default();
+        // The message being delivered, which is returned to the queue if there's an error.
+        let queue_contents: Option<InternalMessage>;
+        // The upstream the message is sent to.
+        let upstream;
         let server;
+        let upstream_available;
+        let dead_letter_client_errors;
         {
             // We don't use counters here, but we have to grab locks in order to prevent a race
-            let _counters = COUNTERS.lock().unwrap();
+            let counters = COUNTERS.lock().unwrap();
             let mut queue = QUEUE.lock().expect("queue lock");
-            queue_contents = queue.pop().map(|internal| {
-                internal_message.size_in_bytes = internal.0.size_in_bytes;
-                internal_message.contents = internal.0.contents.clone();
-                internal_message.sha256 = internal.0.sha256.clone();
-                internal_message.priority = internal.0.priority;
-                internal_message.arrived = internal.0.arrived;
-                internal_message.uuid = internal.0.uuid.clone();
-                internal_message.original_priority = internal.0.original_priority;
-                internal_message.delivery_attempts = internal.0.delivery_attempts + 1;
+            let mut proxy_config = PROXY_CONFIG.lock().unwrap();
+            generation = wakeup::generation();
+            // While every upstream is ejected, messages are left in the queue.
+            let now = time_since_epoch().as_millis();
+            upstream_available = proxy_config.upstreams.is_available(now);
+            let popped = if upstream_available {
+                expiry::pop_unexpired(&counters, server_started, || queue.pop())
+            }
+            else {
+                None
+            };
+            queue_contents = popped.map(|mut internal| {
+                internal.delivery_attempts += 1;
+                history::start(&internal);
+                internal
             });
-            let proxy_config = PROXY_CONFIG.lock().unwrap();
-            server = proxy_config.server.clone();
+            upstream = queue_contents.as_ref().and_then(|_| proxy_config.upstreams.choose(now));
+            server = upstream.map(|u| proxy_config.upstreams.get(u).url.clone()).unwrap_or_default();
+            dead_letter_client_errors = proxy_config.dead_letter_client_errors;
         }
 
+        idle = queue_contents.is_none() && upstream_available;
         let response;
-        if queue_contents != None {
+        if let (Some(mut internal_message), Some(upstream)) = (queue_contents, upstream) {
             let internal_message_json = json!({
                 "contents": &internal_message.contents.clone(),
                 "priority": internal_message.priority.clone(),
+                "original_priority": internal_message.original_priority,
                 "sha256": &internal_message.sha256.clone(),
                 "uuid": &internal_message.uuid.clone(),
+                "queue": &internal_message.queue,
                 // DEBUG
             });
 
@@ -59,21 +86,33 @@ pub fn proxy_loop(server_started: Duration) {
                 .send();
 
             match response {
-                Ok(_) => {
+                Ok(ref r) if r.status().is_success() => {
                     sleep_time = 0;
+                    wal::log_remove(internal_message.uuid, server_started);
+                    dedup::delivered(&internal_message.uuid);
+                    history::finish(&internal_message, history::State::Delivered, None);
+                    let delivered_upstream = {
+                        let mut proxy_config = PROXY_CONFIG.lock().unwrap();
+                        proxy_config.upstreams.delivered(upstream);
+                        proxy_config.upstreams.get(upstream).delivered
+                    };
                     let counters = COUNTERS.lock().unwrap();
                     // A message has been sucessfully removed from the queue.
                     let proxied = counters.proxied.fetch_add(1, Ordering::Relaxed) + 1;
                     let in_queue = counters.in_queue.fetch_sub(1, Ordering::Relaxed) - 1;
                     let bytes_allocated_for_queue = counters.bytes.fetch_sub(internal_message.size_in_bytes, Ordering::Relaxed) - internal_message.size_in_bytes;
+                    counters.queue_proxied(&internal_message.queue, internal_message.size_in_bytes);
                     // Retreive other debug statistics
                     let queue_requests = counters.queue_requests.load(Ordering::Relaxed);
                     let queued = counters.queued.load(Ordering::Relaxed);
 
-                    log::info!("{}|{} message with priority of {} proxied, {} queue_requests, {} queued, {} proxied, {} in {} queue",
+                    log::info!("{}|{} message with priority of {} proxied from '{}' to '{}' ({} delivered), {} queue_requests, {} queued, {} proxied, {} in {} queue",
                         milliseconds_since_timestamp(server_started),
                         Size::Bytes(internal_message.size_in_bytes).to_string(Base::Base10, Style::Abbreviated),
                         internal_message.priority,
+                        internal_message.queue,
+                        server,
+                        delivered_upstream,
                         queue_requests,
                         queued,
                         proxied,
@@ -81,8 +120,73 @@ pub fn proxy_loop(server_started: Duration) {
                         Size::Bytes(bytes_allocated_for_queue).to_string(Base::Base10, Style::Abbreviated),
                     );
                 }
+                Ok(r) => {
+                    let status = r.status();
+                    let error = format!("upstream returned {}", status);
+                    if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                        // The upstream server won't ever accept this message, so don't retry it.
+                        sleep_time = 0;
+                        PROXY_CONFIG.lock().unwrap().upstreams.rejected(upstream);
+                        let counters = COUNTERS.lock().unwrap();
+                        if dead_letter_client_errors {
+                            log::warn!("{}|proxy failure {} to '{}', moving to dead-letter queue: {}",
+                                milliseconds_since_timestamp(server_started),
+                                internal_message.delivery_attempts,
+                                server,
+                                error,
+                            );
+                            dead_letter::dead_letter(&counters, internal_message, &error, server_started);
+                        }
+                        else {
+                            log::warn!("{}|proxy failure {} to '{}', dropping message {}: {}",
+                                milliseconds_since_timestamp(server_started),
+                                internal_message.delivery_attempts,
+                                server,
+                                internal_message.uuid,
+                                error,
+                            );
+                            wal::log_remove(internal_message.uuid, server_started);
+                            dedup::dropped(&internal_message.uuid);
+                            history::finish(&internal_message, history::State::Dropped, Some(&error));
+                            counters.in_queue.fetch_sub(1, Ordering::Relaxed);
+                            counters.bytes.fetch_sub(internal_message.size_in_bytes, Ordering::Relaxed);
+                            counters.queue_removed(&internal_message.queue, internal_message.size_in_bytes);
+                        }
+                    }
+                    else {
+                        // Send the upstream server nothing else for as long as it asks,
+                        // otherwise keep delivering other messages.
+                        sleep_time = 0;
+                        let delay = match status {
+                            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => r.headers().get(RETRY_AFTER)
+                                .and_then(|v| v.to_str().ok())
+                                .and_then(retry_after),
+                            _ => None,
+                        };
+                        if let Some(delay) = delay {
+                            let mut proxy_config = PROXY_CONFIG.lock().unwrap();
+                            proxy_config.upstreams.retry_after(upstream, delay as Timestamp * 1000, time_since_epoch().as_millis());
+                        }
+                        // An upstream that is only limiting how fast we send is still working.
+                        if status != StatusCode::TOO_MANY_REQUESTS {
+                            upstream_failed(upstream, &server, server_started);
+                        }
+                        let backoff = retry::schedule_retry(&mut internal_message);
+                        log::warn!("{}|proxy failure {} to '{}', retrying in {} ms: {}",
+                            milliseconds_since_timestamp(server_started),
+                            internal_message.delivery_attempts,
+                            server,
+                            backoff,
+                            error,
+                        );
+                        let counters = COUNTERS.lock().unwrap();
+                        let mut queue = QUEUE.lock().expect("queue lock");
+                        dead_letter::requeue_or_dead_letter(&counters, &mut queue, internal_message, &error, server_started);
+                    }
+                }
                 Err(e) => {
-                    sleep_time = DEFAULT_DELAY;
+                    // Only this message waits before being retried.
+                    sleep_time = 0;
                     if e.is_server_error() {
                         log::warn!("{}|proxy failure {} to '{}', upstream server error: {}",
                             milliseconds_since_timestamp(server_started),
@@ -127,14 +231,30 @@ pub fn proxy_loop(server_started: Duration) {
                             e
                         );
                     }
-                    let priority = internal_message.priority;
-                    // We don't need counters here, but we have to grab locks in order to avoid a race
-                    let _counters = COUNTERS.lock().unwrap();
+                    upstream_failed(upstream, &server, server_started);
+                    let backoff = retry::schedule_retry(&mut internal_message);
+                    log::debug!("{}|retrying message {} in {} ms",
+                        milliseconds_since_timestamp(server_started),
+                        internal_message.uuid,
+                        backoff,
+                    );
+                    let counters = COUNTERS.lock().unwrap();
                     let mut queue = QUEUE.lock().expect("queue lock");
-                    queue.push(internal_message, priority);
+                    dead_letter::requeue_or_dead_letter(&counters, &mut queue, internal_message, &e.to_string(), server_started);
                 }
             }
         }
+        else if !upstream_available {
+            // Every upstream has been ejected or asked us to wait, so wait for the first
+            // to become available again.
+            let proxy_config = PROXY_CONFIG.lock().unwrap();
+            let wait_time = proxy_config.upstreams.wait_time(time_since_epoch().as_millis());
+            sleep_time = wait_time.div_ceil(1000).max(1) as usize;
+            log::debug!("{}|no upstream available, waiting {} s",
+                milliseconds_since_timestamp(server_started),
+                sleep_time,
+            );
+        }
         else {
             // If the queue is empty, sleep longer.
             let proxy_config = PROXY_CONFIG.lock().unwrap();
@@ -142,4 +262,35 @@ pub fn proxy_loop(server_started: Duration) {
         }
         log::debug!("{}|bottom of proxy loop", milliseconds_since_timestamp(server_started));
     }
-}
\ No newline at end of file
+}
+
+// Record a failed delivery, ejecting the upstream after too many in a row.
+fn upstream_failed(upstream: usize, server: &str, server_started: Duration) {
+    let mut proxy_config = PROXY_CONFIG.lock().unwrap();
+    let upstreams = &mut proxy_config.upstreams;
+    if upstreams.failed(upstream, time_since_epoch().as_millis()) {
+        let stats = upstreams.get(upstream);
+        log::warn!("{}|upstream '{}' ejected for {} s after {} consecutive failures, {} delivered, {} rejected, {} failed, {} ejections",
+            milliseconds_since_timestamp(server_started),
+            server,
+            upstreams.ejection_time / 1000,
+            upstreams.consecutive_failures(upstream),
+            stats.delivered,
+            stats.rejected,
+            stats.failed,
+            stats.ejections,
+        );
+    }
+}
+
+// How many seconds the upstream server asked us to wait, from either form of the
+// Retry-After header.
+pub(crate) fn retry_after(value: &str) -> Option<usize> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    let seconds = date.duration_since(SystemTime::now()).map(|d| d.as_secs()).unwrap_or(0);
+    Some(seconds as usize)
+}
@@ -0,0 +1,46 @@
+use crate::{RETRY_CONFIG, time_since_epoch, InternalMessage, Timestamp};
+
+// The first retry waits this long, in milliseconds, doubling after each failure.
+pub(crate) const DEFAULT_BACKOFF_BASE: Timestamp = 5_000;
+// Retries never wait longer than this, in milliseconds.
+pub(crate) const DEFAULT_BACKOFF_MAX: Timestamp = 300_000;
+// Up to this fraction of each delay is randomly removed, so messages that failed
+// together don't all retry together.
+pub(crate) const DEFAULT_BACKOFF_JITTER: f64 = 0.5;
+
+// How long to wait before retrying messages that failed to be delivered.
+pub(crate) struct RetryConfig {
+    pub(crate) base: Timestamp,
+    pub(crate) max: Timestamp,
+    pub(crate) jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            base: DEFAULT_BACKOFF_BASE,
+            max: DEFAULT_BACKOFF_MAX,
+            jitter: DEFAULT_BACKOFF_JITTER,
+        }
+    }
+}
+
+impl RetryConfig {
+    // The delay in milliseconds after `attempts` failed deliveries, where `random` is
+    // between 0 and 1.
+    pub(crate) fn backoff(&self, attempts: usize, random: f64) -> Timestamp {
+        let exponent = attempts.saturating_sub(1).min(64) as u32;
+        let delay = self.base
+            .saturating_mul(1 << exponent)
+            .min(self.max);
+        delay - (delay as f64 * self.jitter * random) as Timestamp
+    }
+}
+
+// Hold back a message that failed to be delivered until its backoff has passed,
+// returning the delay in milliseconds.
+pub(crate) fn schedule_retry(message: &mut InternalMessage) -> Timestamp {
+    let delay = RETRY_CONFIG.lock().unwrap().backoff(message.delivery_attempts, rand::random());
+    message.not_before = time_since_epoch().as_millis() + delay;
+    delay
+}
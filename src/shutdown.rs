@@ -0,0 +1,235 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use size::{Base, Size, Style};
+
+use crate::{COUNTERS, QUEUE, DELIVERY, LEASES, DEAD_LETTERS, SNAPSHOT_PATH, WAL, milliseconds_since_timestamp, InternalMessage, wal, dedup};
+use crate::store::DEFAULT_QUEUE;
+use crate::dead_letter::DeadLetter;
+
+// Set once a shutdown has been requested, new messages are then rejected.
+pub(crate) static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+// Everything still queued at shutdown, along with the counters.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) queue_requests: usize,
+    pub(crate) proxy_requests: usize,
+    pub(crate) queued: usize,
+    pub(crate) proxied: usize,
+    pub(crate) messages: Vec<InternalMessage>,
+    #[serde(default)]
+    pub(crate) dead_letters: Vec<DeadLetter>,
+}
+
+pub(crate) fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::Relaxed)
+}
+
+// Write the snapshot to a temporary file, then move it into place.
+pub(crate) fn write_snapshot(path: &str, snapshot: &Snapshot) -> io::Result<()> {
+    let temporary_path = format!("{}.tmp", path);
+    {
+        let mut file = File::create(&temporary_path)?;
+        serde_json::to_writer(&mut file, snapshot)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        file.flush()?;
+        file.sync_all()?;
+    }
+    fs::rename(&temporary_path, path)
+}
+
+// Read the snapshot at `path`, if there is one.
+pub(crate) fn read_snapshot(path: &str) -> io::Result<Option<Snapshot>> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_reader(BufReader::new(file))
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Stop accepting messages, wait for any in-flight delivery to finish, then save
+// what's left in the queue and exit.
+pub(crate) fn shutdown(server_started: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    log::info!("{}|shutting down, no longer accepting messages",
+        milliseconds_since_timestamp(server_started),
+    );
+
+    // Holding this prevents the proxy and notify loops from starting another delivery.
+    let _delivery = DELIVERY.lock().unwrap_or_else(|e| e.into_inner());
+
+    let path = SNAPSHOT_PATH.lock().unwrap().clone();
+    if path.is_empty() {
+        log::info!("{}|snapshot disabled, exiting",
+            milliseconds_since_timestamp(server_started),
+        );
+        process::exit(0);
+    }
+
+    let counters = COUNTERS.lock().unwrap_or_else(|e| e.into_inner());
+    let mut queue = QUEUE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut snapshot = Snapshot {
+        queue_requests: counters.queue_requests.load(Ordering::Relaxed),
+        proxy_requests: counters.proxy_requests.load(Ordering::Relaxed),
+        queued: counters.queued.load(Ordering::Relaxed),
+        proxied: counters.proxied.load(Ordering::Relaxed),
+        messages: Vec::new(),
+        dead_letters: DEAD_LETTERS.lock().unwrap_or_else(|e| e.into_inner()).drain(),
+    };
+    // Leased messages haven't been acknowledged, so they are saved too.
+    snapshot.messages.extend(LEASES.lock().unwrap_or_else(|e| e.into_inner()).drain());
+    snapshot.messages.extend(queue.drain());
+    let bytes: usize = snapshot.messages.iter().map(|m| m.size_in_bytes).sum();
+
+    match write_snapshot(&path, &snapshot) {
+        Ok(_) => {
+            log::info!("{}|saved {} messages ({}) and {} dead letters to snapshot '{}'",
+                milliseconds_since_timestamp(server_started),
+                snapshot.messages.len(),
+                Size::Bytes(bytes).to_string(Base::Base10, Style::Abbreviated),
+                snapshot.dead_letters.len(),
+                path,
+            );
+            // Everything is in the snapshot now, so don't also restore it from the log. If
+            // we stop before the log is cleared, `restore` skips messages found in both.
+            let wal = WAL.lock().unwrap_or_else(|e| e.into_inner());
+            if wal.is_enabled() {
+                if let Err(e) = wal::compact(wal.path(), &[], &[]) {
+                    log::error!("{}|failed to clear write-ahead log '{}': {}",
+                        milliseconds_since_timestamp(server_started),
+                        wal.path(),
+                        e,
+                    );
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("{}|failed to save snapshot '{}': {}",
+                milliseconds_since_timestamp(server_started),
+                path,
+                e,
+            );
+            // Persistent backends still hold a copy if the messages are put back.
+            for message in snapshot.messages {
+                let _ = queue.push(message);
+            }
+            process::exit(1);
+        }
+    }
+    process::exit(0);
+}
+
+// Reload the queue and counters from the snapshot left by the last shutdown.
+pub(crate) fn restore(server_started: Duration) {
+    let path = SNAPSHOT_PATH.lock().unwrap().clone();
+    if path.is_empty() {
+        return;
+    }
+
+    let snapshot = match read_snapshot(&path) {
+        Ok(Some(s)) => s,
+        Ok(None) => return,
+        Err(e) => {
+            log::error!("{}|failed to read snapshot '{}': {}",
+                milliseconds_since_timestamp(server_started),
+                path,
+                e,
+            );
+            return;
+        }
+    };
+
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    counters.queue_requests.fetch_add(snapshot.queue_requests, Ordering::Relaxed);
+    counters.proxy_requests.fetch_add(snapshot.proxy_requests, Ordering::Relaxed);
+    counters.queued.fetch_add(snapshot.queued, Ordering::Relaxed);
+    counters.proxied.fetch_add(snapshot.proxied, Ordering::Relaxed);
+
+    let mut restored = 0;
+    let mut restored_bytes = 0;
+    for mut message in snapshot.messages {
+        let size_of_request = message.size_in_bytes;
+        let uuid = message.uuid;
+        // Stopping after the snapshot was saved but before the write-ahead log was
+        // cleared, or after restoring the snapshot but before deleting it, leaves
+        // messages in both. Those have already been restored from the log.
+        if queue.find(&uuid).is_some() {
+            log::debug!("{}|message {} was already restored from the write-ahead log",
+                milliseconds_since_timestamp(server_started),
+                uuid,
+            );
+            continue;
+        }
+        if !queue.contains(&message.queue) {
+            log::warn!("{}|queue '{}' is no longer configured, restoring message {} to the default queue",
+                milliseconds_since_timestamp(server_started),
+                message.queue,
+                uuid,
+            );
+            message.queue = DEFAULT_QUEUE.to_string();
+        }
+        let name = message.queue.clone();
+        // Restored messages must also be in the write-ahead log in case of a crash.
+        if let Err(e) = wal::log_push(&message) {
+            log::error!("{}|failed to write restored message {} to write-ahead log: {}",
+                milliseconds_since_timestamp(server_started),
+                uuid,
+                e,
+            );
+        }
+        dedup::queued(&message);
+        if let Err(e) = queue.push(message) {
+            log::error!("{}|failed to restore message {}: {}",
+                milliseconds_since_timestamp(server_started),
+                uuid,
+                e,
+            );
+            continue;
+        }
+        counters.queue_restored(&name, size_of_request);
+        restored += 1;
+        restored_bytes += size_of_request;
+    }
+    counters.in_queue.fetch_add(restored, Ordering::Relaxed);
+    counters.bytes.fetch_add(restored_bytes, Ordering::Relaxed);
+
+    let mut restored_dead_letters = 0;
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+    for letter in snapshot.dead_letters {
+        // As with messages, dead letters may also have been restored from the log.
+        if dead_letters.get(&letter.message.uuid).is_some() {
+            continue;
+        }
+        wal::log_dead_letter(&letter, server_started);
+        if dead_letters.insert(letter).is_none() {
+            counters.dead_letters.fetch_add(1, Ordering::Relaxed);
+        }
+        restored_dead_letters += 1;
+    }
+    drop(dead_letters);
+
+    log::info!("{}|restored {} messages ({}) and {} dead letters from snapshot '{}'",
+        milliseconds_since_timestamp(server_started),
+        restored,
+        Size::Bytes(restored_bytes).to_string(Base::Base10, Style::Abbreviated),
+        restored_dead_letters,
+        path,
+    );
+
+    // The snapshot has been loaded, don't restore it again on the next start.
+    if let Err(e) = fs::remove_file(&path) {
+        log::error!("{}|failed to remove snapshot '{}': {}",
+            milliseconds_since_timestamp(server_started),
+            path,
+            e,
+        );
+    }
+}
@@ -0,0 +1,629 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use priority_queue::PriorityQueue;
+use uuid::Uuid;
+
+use crate::{time_since_epoch, InternalMessage, Priority, Timestamp};
+use crate::disk::DiskStore;
+use crate::aging::AgingConfig;
+use crate::wakeup;
+
+// Messages are numbered as they are queued, zero is never used.
+static SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+// The number of the next message to be queued.
+pub(crate) fn next_sequence() -> u64 {
+    SEQUENCE.fetch_add(1, Ordering::Relaxed)
+}
+
+// Make sure messages queued from now on are numbered after a stored message, for
+// example one restored from a previous run.
+pub(crate) fn observe_sequence(sequence: u64) {
+    SEQUENCE.fetch_max(sequence + 1, Ordering::Relaxed);
+}
+
+// Messages are popped highest priority first, and in the order they were queued
+// within the same priority.
+type Rank = (Priority, Reverse<u64>);
+
+fn rank(message: &InternalMessage) -> Rank {
+    (message.priority, Reverse(message.sequence))
+}
+
+// Storage for queued messages. Messages are popped highest priority first.
+pub(crate) trait QueueStore: Send {
+    // Add a message. Capacity is checked separately with `has_room`, so that a
+    // message that failed to be delivered can always be returned to the queue.
+    fn push(&mut self, message: InternalMessage) -> io::Result<()>;
+    fn pop(&mut self) -> Option<InternalMessage>;
+    fn peek(&self) -> Option<InternalMessage>;
+    fn find(&self, uuid: &Uuid) -> Option<InternalMessage>;
+    // Messages with at least `min_priority` in the order they would be delivered,
+    // skipping the first `offset` and returning at most `limit`.
+    fn list(&self, offset: usize, limit: usize, min_priority: Priority) -> Vec<InternalMessage>;
+    // Change the priority of a message at `now`, returning its previous priority.
+    fn reprioritize(&mut self, uuid: &Uuid, priority: Priority, now: Timestamp) -> Option<Priority>;
+    // The uuids of all messages matching `predicate`.
+    fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid>;
+    fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage>;
+    fn len(&self) -> usize;
+    fn bytes(&self) -> usize;
+    // Whether a new message of this size can be accepted.
+    fn has_room(&self, size_in_bytes: usize) -> bool;
+    // Raise the priority of messages that have been waiting, returning how many changed.
+    fn age(&mut self, now: Timestamp, aging: &AgingConfig) -> usize;
+    // The uuids of all messages that expired before `now`.
+    fn expired(&self, now: Timestamp) -> Vec<Uuid>;
+    // How many messages would be delivered before a new message with this priority,
+    // as those with a higher priority and those with the same priority.
+    fn position(&self, priority: Priority) -> (usize, usize);
+
+    // How many messages and bytes have been moved out of memory onto disk.
+    fn overflow(&self) -> (usize, usize) {
+        (0, 0)
+    }
+
+    // Pop the next message only if `accept` allows it.
+    fn pop_if(&mut self, accept: &dyn Fn(&InternalMessage) -> bool) -> Option<InternalMessage> {
+        if !accept(&self.peek()?) {
+            return None;
+        }
+        self.pop()
+    }
+
+    // Remove every message, including any that can't be popped yet.
+    fn drain(&mut self) -> Vec<InternalMessage> {
+        let mut messages = Vec::new();
+        while let Some(message) = self.pop() {
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+// In-memory queue, optionally storing messages that don't fit in memory on disk.
+pub(crate) struct MemoryStore {
+    queue: PriorityQueue<InternalMessage, Rank>,
+    bytes: usize,
+    limit: usize,
+    overflow: Option<DiskStore>,
+}
+
+impl MemoryStore {
+    pub(crate) fn new(limit: usize, overflow: Option<DiskStore>) -> MemoryStore {
+        MemoryStore {
+            queue: PriorityQueue::new(),
+            bytes: 0,
+            limit,
+            overflow,
+        }
+    }
+
+    fn push_to_memory(&mut self, message: InternalMessage) {
+        self.bytes += message.size_in_bytes;
+        observe_sequence(message.sequence);
+        let rank = rank(&message);
+        self.queue.push(message, rank);
+    }
+
+    // Store a message that doesn't fit in memory. The highest priority messages are
+    // kept in memory: if the new message outranks everything already on disk, lower
+    // priority messages are moved out of memory to make room for it. If writing to
+    // disk fails, messages are kept in memory instead.
+    fn spill(&mut self, message: InternalMessage) {
+        let overflow = self.overflow.as_mut().unwrap();
+        let size_of_request = message.size_in_bytes;
+
+        let outranks_disk = match overflow.highest_priority() {
+            Some(priority) => message.priority > priority,
+            None => true,
+        };
+        if outranks_disk && size_of_request <= self.limit {
+            while self.bytes + size_of_request > self.limit {
+                // Find the lowest priority, most recently queued message in memory.
+                let lowest = self.queue.iter()
+                    .min_by_key(|(_, rank)| **rank)
+                    .map(|(m, rank)| (m.uuid, rank.0, m.size_in_bytes));
+                let uuid = match lowest {
+                    Some((uuid, p, size)) if p < message.priority && overflow.has_room(size) => uuid,
+                    _ => break,
+                };
+                let evicted = self.queue.get(&uuid).unwrap().0;
+                log::debug!("moving message {} with priority of {} to disk", evicted.uuid, evicted.priority);
+                if let Err(e) = overflow.push(evicted.clone()) {
+                    log::warn!("failed to move message {} to disk: {}", uuid, e);
+                    break;
+                }
+                let (evicted, _) = self.queue.remove(&uuid).unwrap();
+                self.bytes -= evicted.size_in_bytes;
+            }
+            if self.bytes + size_of_request <= self.limit {
+                self.push_to_memory(message);
+                return;
+            }
+        }
+
+        log::debug!("storing message {} with priority of {} on disk", message.uuid, message.priority);
+        if let Err(e) = overflow.push(message.clone()) {
+            log::warn!("failed to store message {} on disk, keeping it in memory: {}", message.uuid, e);
+            self.push_to_memory(message);
+        }
+    }
+
+    // Move messages from disk back into memory as space allows.
+    fn page_in(&mut self) {
+        loop {
+            let available = self.limit.saturating_sub(self.bytes);
+            let message = match self.overflow.as_mut().and_then(|o| o.pop_if_fits(available)) {
+                None => break,
+                Some(Ok(m)) => m,
+                Some(Err(e)) => {
+                    log::error!("failed to move message from disk to memory, dropping it: {}", e);
+                    continue;
+                }
+            };
+            self.push_to_memory(message);
+        }
+    }
+}
+
+impl QueueStore for MemoryStore {
+    fn push(&mut self, message: InternalMessage) -> io::Result<()> {
+        let fits = self.bytes + message.size_in_bytes <= self.limit;
+        let spill = match self.overflow.as_ref() {
+            // Equal priority messages already on disk have to be delivered first.
+            Some(o) => !fits || o.highest_priority().is_some_and(|p| message.priority <= p),
+            None => false,
+        };
+        if spill {
+            self.spill(message);
+        }
+        else {
+            self.push_to_memory(message);
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<InternalMessage> {
+        self.page_in();
+        // Messages on disk can age past everything in memory.
+        let in_memory = self.queue.peek().map(|(_, rank)| rank.0);
+        let on_disk = self.overflow.as_ref().and_then(|o| o.highest_priority());
+        if let (Some(priority), Some(disk_priority)) = (in_memory, on_disk) {
+            if disk_priority > priority {
+                return self.overflow.as_mut().and_then(|o| o.pop());
+            }
+        }
+        match self.queue.pop() {
+            Some((message, _)) => {
+                self.bytes -= message.size_in_bytes;
+                Some(message)
+            }
+            // Anything left on disk is too big to ever fit in memory.
+            None => self.overflow.as_mut().and_then(|o| o.pop()),
+        }
+    }
+
+    fn peek(&self) -> Option<InternalMessage> {
+        let in_memory = self.queue.peek().map(|(message, rank)| (message, rank.0));
+        let on_disk = self.overflow.as_ref().and_then(|o| o.highest_priority());
+        match (in_memory, on_disk) {
+            (Some((message, priority)), Some(disk_priority)) if priority >= disk_priority => Some(message.clone()),
+            (Some((message, _)), None) => Some(message.clone()),
+            _ => self.overflow.as_ref().and_then(|o| o.peek()),
+        }
+    }
+
+    fn find(&self, uuid: &Uuid) -> Option<InternalMessage> {
+        match self.queue.get(uuid) {
+            Some((message, _)) => Some(message.clone()),
+            None => self.overflow.as_ref().and_then(|o| o.find(uuid)),
+        }
+    }
+
+    fn list(&self, offset: usize, limit: usize, min_priority: Priority) -> Vec<InternalMessage> {
+        let mut listed: Vec<&InternalMessage> = self.queue.iter()
+            .filter(|(_, rank)| rank.0 >= min_priority)
+            .map(|(message, _)| message)
+            .collect();
+        listed.sort_by_key(|message| Reverse(rank(message)));
+        let end = offset.saturating_add(limit);
+        // Only as much as could be listed is read from disk, then merged in order.
+        let on_disk = self.overflow.as_ref().map_or_else(Vec::new, |o| o.list(0, end, min_priority));
+        let mut messages = Vec::new();
+        let (mut memory, mut disk) = (listed.into_iter().peekable(), on_disk.into_iter().peekable());
+        while messages.len() < end {
+            let next = match (memory.peek(), disk.peek()) {
+                (Some(m), Some(d)) if rank(d) > rank(m) => disk.next(),
+                (Some(_), _) => memory.next().cloned(),
+                (None, _) => disk.next(),
+            };
+            match next {
+                Some(message) => messages.push(message),
+                None => break,
+            }
+        }
+        messages.into_iter().skip(offset).collect()
+    }
+
+    fn reprioritize(&mut self, uuid: &Uuid, priority: Priority, now: Timestamp) -> Option<Priority> {
+        match self.queue.get_mut(uuid) {
+            Some((message, rank)) => {
+                let previous = message.reprioritize(priority, now);
+                let rank = (priority, rank.1);
+                self.queue.change_priority(uuid, rank);
+                Some(previous)
+            }
+            None => self.overflow.as_mut()?.reprioritize(uuid, priority, now),
+        }
+    }
+
+    fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid> {
+        let mut selected: Vec<Uuid> = self.queue.iter()
+            .filter(|(message, _)| predicate(message))
+            .map(|(message, _)| message.uuid)
+            .collect();
+        if let Some(overflow) = self.overflow.as_ref() {
+            selected.extend(overflow.select(predicate));
+        }
+        selected
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage> {
+        match self.queue.remove(uuid) {
+            Some((message, _)) => {
+                self.bytes -= message.size_in_bytes;
+                Some(message)
+            }
+            None => self.overflow.as_mut().and_then(|o| o.remove(uuid)),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.queue.len() + self.overflow.as_ref().map_or(0, |o| o.len())
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes + self.overflow.as_ref().map_or(0, |o| o.bytes())
+    }
+
+    fn has_room(&self, size_in_bytes: usize) -> bool {
+        self.bytes + size_in_bytes <= self.limit
+            || self.overflow.as_ref().is_some_and(|o| o.has_room(size_in_bytes))
+    }
+
+    fn overflow(&self) -> (usize, usize) {
+        self.overflow.as_ref().map_or((0, 0), |o| (o.len(), o.bytes()))
+    }
+
+    fn age(&mut self, now: Timestamp, aging: &AgingConfig) -> usize {
+        let aged: Vec<(Uuid, Priority)> = self.queue.iter()
+            .filter_map(|(message, _)| {
+                let (base, since) = message.aging_base();
+                let priority = aging.priority(base, message.priority, since, now);
+                if priority > message.priority { Some((message.uuid, priority)) } else { None }
+            })
+            .collect();
+        for (uuid, priority) in &aged {
+            // The priority is also kept on the message, so it is reported correctly.
+            if let Some((message, rank)) = self.queue.get_mut(uuid) {
+                message.priority = *priority;
+                let rank = (*priority, rank.1);
+                self.queue.change_priority(uuid, rank);
+            }
+        }
+        let mut count = aged.len();
+        if let Some(overflow) = self.overflow.as_mut() {
+            count += overflow.age(now, aging);
+        }
+        count
+    }
+
+    fn position(&self, priority: Priority) -> (usize, usize) {
+        let (mut higher, mut same) = self.overflow.as_ref().map_or((0, 0), |o| o.position(priority));
+        for (_, rank) in self.queue.iter() {
+            if rank.0 > priority {
+                higher += 1;
+            }
+            else if rank.0 == priority {
+                same += 1;
+            }
+        }
+        (higher, same)
+    }
+
+    fn expired(&self, now: Timestamp) -> Vec<Uuid> {
+        let mut expired: Vec<Uuid> = self.queue.iter()
+            .filter(|(message, _)| message.is_expired(now))
+            .map(|(message, _)| message.uuid)
+            .collect();
+        if let Some(overflow) = self.overflow.as_ref() {
+            expired.extend(overflow.expired(now));
+        }
+        expired
+    }
+}
+
+// Holds messages that can't be delivered yet in memory, in front of another store.
+// Messages are moved into the store once their `not_before` time has passed, but
+// count against its limit while they wait.
+pub(crate) struct ScheduledStore {
+    store: Box<dyn QueueStore>,
+    waiting: BTreeMap<(Timestamp, Uuid), InternalMessage>,
+    waiting_bytes: usize,
+}
+
+impl ScheduledStore {
+    pub(crate) fn new(store: Box<dyn QueueStore>) -> ScheduledStore {
+        ScheduledStore {
+            store,
+            waiting: BTreeMap::new(),
+            waiting_bytes: 0,
+        }
+    }
+
+    // Move messages that are now eligible for delivery into the store.
+    fn release(&mut self) {
+        let now = time_since_epoch().as_millis();
+        while let Some(key) = self.waiting.keys().next().copied() {
+            if key.0 > now {
+                break;
+            }
+            let message = self.waiting.remove(&key).unwrap();
+            self.waiting_bytes -= message.size_in_bytes;
+            let uuid = message.uuid;
+            if let Err(e) = self.store.push(message) {
+                log::error!("failed to release scheduled message {}: {}", uuid, e);
+            }
+        }
+    }
+}
+
+impl QueueStore for ScheduledStore {
+    fn push(&mut self, message: InternalMessage) -> io::Result<()> {
+        if message.not_before > time_since_epoch().as_millis() {
+            self.waiting_bytes += message.size_in_bytes;
+            self.waiting.insert((message.not_before, message.uuid), message);
+            Ok(())
+        }
+        else {
+            self.store.push(message)
+        }
+    }
+
+    fn pop(&mut self) -> Option<InternalMessage> {
+        self.release();
+        self.store.pop()
+    }
+
+    // Messages that are due but haven't been released yet are included.
+    fn peek(&self) -> Option<InternalMessage> {
+        let now = time_since_epoch().as_millis();
+        let due = self.waiting.iter()
+            .take_while(|((not_before, _), _)| *not_before <= now)
+            .map(|(_, message)| message)
+            .max_by_key(|message| rank(message));
+        match (self.store.peek(), due) {
+            (Some(message), Some(due)) if rank(due) > rank(&message) => Some(due.clone()),
+            (Some(message), _) => Some(message),
+            (None, due) => due.cloned(),
+        }
+    }
+
+    // Due messages are released first, so they are peeked at as well.
+    fn pop_if(&mut self, accept: &dyn Fn(&InternalMessage) -> bool) -> Option<InternalMessage> {
+        self.release();
+        self.store.pop_if(accept)
+    }
+
+    fn find(&self, uuid: &Uuid) -> Option<InternalMessage> {
+        self.store.find(uuid).or_else(|| self.waiting.values().find(|m| m.uuid == *uuid).cloned())
+    }
+
+    // Messages that aren't due yet come last, in the order they become due.
+    fn list(&self, offset: usize, limit: usize, min_priority: Priority) -> Vec<InternalMessage> {
+        let end = offset.saturating_add(limit);
+        self.store.list(0, end, min_priority).into_iter()
+            .chain(self.waiting.values().filter(|m| m.priority >= min_priority).cloned())
+            .skip(offset)
+            .take(limit)
+            .collect()
+    }
+
+    fn reprioritize(&mut self, uuid: &Uuid, priority: Priority, now: Timestamp) -> Option<Priority> {
+        if let Some(previous) = self.store.reprioritize(uuid, priority, now) {
+            return Some(previous);
+        }
+        let message = self.waiting.values_mut().find(|m| m.uuid == *uuid)?;
+        Some(message.reprioritize(priority, now))
+    }
+
+    fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid> {
+        let mut selected = self.store.select(predicate);
+        selected.extend(self.waiting.values().filter(|m| predicate(m)).map(|m| m.uuid));
+        selected
+    }
+
+    fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage> {
+        if let Some(message) = self.store.remove(uuid) {
+            return Some(message);
+        }
+        let key = *self.waiting.keys().find(|key| key.1 == *uuid)?;
+        let message = self.waiting.remove(&key)?;
+        self.waiting_bytes -= message.size_in_bytes;
+        Some(message)
+    }
+
+    fn len(&self) -> usize {
+        self.store.len() + self.waiting.len()
+    }
+
+    fn bytes(&self) -> usize {
+        self.store.bytes() + self.waiting_bytes
+    }
+
+    fn has_room(&self, size_in_bytes: usize) -> bool {
+        self.store.has_room(size_in_bytes + self.waiting_bytes)
+    }
+
+    fn overflow(&self) -> (usize, usize) {
+        self.store.overflow()
+    }
+
+    // Messages that aren't due yet age too, so they are listed with their effective priority.
+    fn age(&mut self, now: Timestamp, aging: &AgingConfig) -> usize {
+        let mut count = self.store.age(now, aging);
+        for message in self.waiting.values_mut() {
+            let (base, since) = message.aging_base();
+            let priority = aging.priority(base, message.priority, since, now);
+            if priority > message.priority {
+                message.priority = priority;
+                count += 1;
+            }
+        }
+        count
+    }
+
+    // Messages that aren't eligible for delivery yet aren't counted.
+    fn position(&self, priority: Priority) -> (usize, usize) {
+        self.store.position(priority)
+    }
+
+    fn expired(&self, now: Timestamp) -> Vec<Uuid> {
+        let mut expired = self.store.expired(now);
+        expired.extend(self.waiting.values()
+            .filter(|message| message.is_expired(now))
+            .map(|message| message.uuid));
+        expired
+    }
+
+    fn drain(&mut self) -> Vec<InternalMessage> {
+        let mut messages = self.store.drain();
+        self.waiting_bytes = 0;
+        messages.extend(std::mem::take(&mut self.waiting).into_values());
+        messages
+    }
+}
+
+// The name of the queue used by the `/` routes.
+pub(crate) const DEFAULT_QUEUE: &str = "default";
+
+// All named queues, each with its own store.
+pub(crate) struct Queues {
+    stores: BTreeMap<String, Box<dyn QueueStore>>,
+    // The queue to check first on the next `pop`.
+    next: usize,
+}
+
+impl Queues {
+    pub(crate) fn new() -> Queues {
+        Queues {
+            stores: BTreeMap::new(),
+            next: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, name: &str, store: Box<dyn QueueStore>) {
+        self.stores.insert(name.to_string(), store);
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.stores.contains_key(name)
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&dyn QueueStore> {
+        self.stores.get(name).map(|store| store.as_ref())
+    }
+
+    pub(crate) fn get_mut(&mut self, name: &str) -> Option<&mut (dyn QueueStore + 'static)> {
+        self.stores.get_mut(name).map(|store| store.as_mut())
+    }
+
+    // Add a message to the queue it names.
+    pub(crate) fn push(&mut self, mut message: InternalMessage) -> io::Result<()> {
+        // Messages logged before they were numbered are numbered as they're restored.
+        if message.sequence == 0 {
+            message.sequence = next_sequence();
+        }
+        let pushed = match self.stores.get_mut(&message.queue) {
+            Some(store) => store.push(message),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown queue '{}'", message.queue))),
+        };
+        if pushed.is_ok() {
+            wakeup::notify();
+        }
+        pushed
+    }
+
+    // Pop the highest priority message from each non-empty queue in turn, so that a
+    // busy queue can't starve the others.
+    pub(crate) fn pop(&mut self) -> Option<InternalMessage> {
+        let count = self.stores.len();
+        for i in 0..count {
+            let index = (self.next + i) % count;
+            let store = self.stores.values_mut().nth(index).unwrap();
+            if let Some(message) = store.pop() {
+                self.next = index + 1;
+                return Some(message);
+            }
+        }
+        None
+    }
+
+    // Find a message in any queue.
+    pub(crate) fn find(&self, uuid: &Uuid) -> Option<InternalMessage> {
+        self.stores.values().find_map(|store| store.find(uuid))
+    }
+
+    // The uuids of messages in any queue matching `predicate`.
+    pub(crate) fn select(&self, predicate: &dyn Fn(&InternalMessage) -> bool) -> Vec<Uuid> {
+        self.stores.values().flat_map(|store| store.select(predicate)).collect()
+    }
+
+    // Change the priority of a message in whichever queue it is in.
+    pub(crate) fn reprioritize(&mut self, uuid: &Uuid, priority: Priority, now: Timestamp) -> Option<Priority> {
+        self.stores.values_mut().find_map(|store| store.reprioritize(uuid, priority, now))
+    }
+
+    // Remove a message from whichever queue it is in.
+    pub(crate) fn remove(&mut self, uuid: &Uuid) -> Option<InternalMessage> {
+        self.stores.values_mut().find_map(|store| store.remove(uuid))
+    }
+
+    // Raise the priority of messages that have been waiting in every queue.
+    pub(crate) fn age(&mut self, now: Timestamp, aging: &AgingConfig) -> usize {
+        self.stores.values_mut().map(|store| store.age(now, aging)).sum()
+    }
+
+    // Remove every message that expired before `now` from every queue.
+    pub(crate) fn remove_expired(&mut self, now: Timestamp) -> Vec<InternalMessage> {
+        let mut expired = Vec::new();
+        for store in self.stores.values_mut() {
+            for uuid in store.expired(now) {
+                expired.extend(store.remove(&uuid));
+            }
+        }
+        expired
+    }
+
+    // Copies of every message in every queue.
+    pub(crate) fn messages(&self) -> Vec<InternalMessage> {
+        self.stores.values().flat_map(|store| store.list(0, usize::MAX, 0)).collect()
+    }
+
+    // Remove every message from every queue.
+    pub(crate) fn drain(&mut self) -> Vec<InternalMessage> {
+        self.stores.values_mut().flat_map(|store| store.drain()).collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.stores.values().map(|store| store.len()).sum()
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.stores.values().map(|store| store.bytes()).sum()
+    }
+}
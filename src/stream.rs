@@ -0,0 +1,146 @@
+use std::io::{self, Read};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::{COUNTERS, QUEUE, LEASES, milliseconds_since_timestamp, message_json, dead_letter, expiry, shutdown, wakeup, Timestamp};
+
+// Send a comment this often when there's nothing else to send, in seconds, so that
+// consumers that have gone away are noticed.
+const KEEP_ALIVE: u64 = 5;
+
+// A consumer receiving messages from a queue as Server-Sent Events. Each message is
+// leased, and only `credits` messages can be waiting to be acknowledged at once. When
+// the consumer disconnects, messages it hadn't acknowledged are returned to the queue.
+pub(crate) struct Subscription {
+    queue: String,
+    credits: usize,
+    // How long consumers have to acknowledge each message, in seconds.
+    visibility_timeout: usize,
+    // Receipts for messages that may not have been acknowledged yet.
+    receipts: Vec<Uuid>,
+    // The event being sent, and how much of it has been sent.
+    event: Vec<u8>,
+    sent: usize,
+    // Once an event is sent, the response is flushed so the consumer gets it now.
+    flush: bool,
+    last_event: Instant,
+    server_started: Duration,
+    // The stream holds a worker for as long as it is open.
+    _waiter: wakeup::Waiter,
+}
+
+impl Subscription {
+    pub(crate) fn new(queue: &str, credits: usize, visibility_timeout: usize, waiter: wakeup::Waiter, server_started: Duration) -> Subscription {
+        log::info!("{}|consumer subscribed to '{}' with {} credits",
+            milliseconds_since_timestamp(server_started),
+            queue,
+            credits,
+        );
+        Subscription {
+            queue: queue.to_string(),
+            credits,
+            visibility_timeout,
+            receipts: Vec::new(),
+            event: Vec::new(),
+            sent: 0,
+            flush: false,
+            last_event: Instant::now(),
+            server_started,
+            _waiter: waiter,
+        }
+    }
+
+    // Lease the next message as an event, if the consumer has credit for it.
+    fn next_event(&mut self) -> Option<Vec<u8>> {
+        let counters = COUNTERS.lock().unwrap();
+        let mut queue = QUEUE.lock().expect("queue lock");
+        let mut leases = LEASES.lock().unwrap();
+        // Acknowledged, rejected and expired leases give credit back.
+        self.receipts.retain(|receipt| leases.contains(receipt));
+        if self.receipts.len() >= self.credits {
+            return None;
+        }
+        let store = queue.get_mut(&self.queue)?;
+        let message = expiry::pop_unexpired(&counters, self.server_started, || store.pop())?;
+        let receipt = leases.lease(message.clone(), self.visibility_timeout as Timestamp * 1000);
+        self.receipts.push(receipt);
+        counters.leased.fetch_add(1, Ordering::Relaxed);
+        log::info!("{}|message {} with priority of {} streamed from '{}'",
+            milliseconds_since_timestamp(self.server_started),
+            message.uuid,
+            message.priority,
+            self.queue,
+        );
+        let data = message_json(&message, Some(receipt), self.visibility_timeout);
+        Some(format!("id: {}\nevent: message\ndata: {}\n\n", message.uuid, data).into_bytes())
+    }
+}
+
+impl Read for Subscription {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let remaining = self.event.len() - self.sent;
+            if remaining > 0 {
+                // Rocket ends the response if a flush is requested before anything is
+                // read into its buffer, so the end of an event is never allowed to fill
+                // it, and the flush always follows part of the event.
+                let length = if remaining == buf.len() { remaining - 1 } else { remaining.min(buf.len()) };
+                if length == 0 {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "flush"));
+                }
+                buf[..length].copy_from_slice(&self.event[self.sent..self.sent + length]);
+                self.sent += length;
+                return Ok(length);
+            }
+            if self.flush {
+                self.flush = false;
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "flush"));
+            }
+            if shutdown::is_shutting_down() {
+                return Ok(0);
+            }
+
+            let generation = wakeup::generation();
+            let event = match self.next_event() {
+                Some(event) => Some(event),
+                None if self.last_event.elapsed() >= Duration::from_secs(KEEP_ALIVE) => Some(b": keep-alive\n\n".to_vec()),
+                None => None,
+            };
+            match event {
+                Some(event) => {
+                    self.event = event;
+                    self.sent = 0;
+                    self.flush = true;
+                    self.last_event = Instant::now();
+                }
+                // Wait for a message, or for an acknowledgement to give credit back.
+                None => {
+                    wakeup::wait(generation, Duration::from_secs(1));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let counters = COUNTERS.lock().unwrap();
+        let mut queue = QUEUE.lock().expect("queue lock");
+        let unacknowledged: Vec<_> = {
+            let mut leases = LEASES.lock().unwrap();
+            self.receipts.iter().filter_map(|receipt| leases.take(receipt)).collect()
+        };
+        log::info!("{}|consumer of '{}' disconnected, returning {} unacknowledged messages",
+            milliseconds_since_timestamp(self.server_started),
+            self.queue,
+            unacknowledged.len(),
+        );
+        counters.leased.fetch_sub(unacknowledged.len(), Ordering::Relaxed);
+        for mut message in unacknowledged {
+            message.delivery_attempts += 1;
+            dead_letter::requeue_or_dead_letter(&counters, &mut queue, message, "consumer disconnected", self.server_started);
+        }
+    }
+}
@@ -1,10 +1,34 @@
-use crate::rocket;
+use crate::{rocket, time_since_epoch, wal, proxy, notify, expiry, wakeup, InternalMessage, NotifyConfig, COUNTERS, QUEUE, WAL, LEASES, NOTIFY_CONFIG};
+use sha2::{Sha256, Digest};
+use crate::wal::{WriteAheadLog, WalEntry};
+use crate::disk::DiskStore;
+use crate::store::{self, QueueStore, MemoryStore, ScheduledStore};
+use crate::shutdown::{self, Snapshot};
+use crate::lease::Leases;
+use crate::retry::RetryConfig;
+use crate::upstream::{Upstream, Upstreams, Strategy};
+use crate::aging::{self, AgingConfig};
+use crate::dedup::Dedup;
+use crate::history::Reprioritized;
 use rocket::local::Client;
-use rocket::http::{Status, ContentType};
+use rocket::http::{Status, ContentType, Header};
+use uuid::Uuid;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use std::io::Read;
+
+// Each rocket instance replaces the global queue, so tests using one can't run in parallel.
+static SERIAL: Mutex<()> = Mutex::new(());
 
 #[test]
 fn invalid_content() {
-    let client = Client::new(rocket()).unwrap();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
 
     // Try to get a message when the queue is empty.
     let mut res = client.get("/").header(ContentType::JSON).dispatch();
@@ -40,7 +64,8 @@ fn invalid_content() {
 
 #[test]
 fn post_and_get() {
-    let client = Client::new(rocket()).unwrap();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
 
     // Start with an empty queue.
     let res = client.get("/").header(ContentType::JSON).dispatch();
@@ -96,7 +121,8 @@ fn post_and_get() {
 
 #[test]
 fn post_priority_and_get() {
-    let client = Client::new(rocket()).unwrap();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
 
     // Start with an empty queue.
     let res = client.get("/").header(ContentType::JSON).dispatch();
@@ -161,4 +187,1926 @@ fn post_priority_and_get() {
     // The queue is empty again
     let res = client.get("/").header(ContentType::JSON).dispatch();
     assert_eq!(res.status(), Status::NotFound);
-}
\ No newline at end of file
+}
+
+#[test]
+fn write_ahead_log_replay() {
+    let path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+
+    let first = InternalMessage {
+        contents: "Item one".to_string(),
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    };
+    let second = InternalMessage {
+        contents: "Item two".to_string(),
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    };
+
+    // Queue two items, then remove the first.
+    let mut log = WriteAheadLog::open(path).unwrap();
+    log.append(&WalEntry::Push { message: Box::new(first.clone()) }).unwrap();
+    log.append(&WalEntry::Push { message: Box::new(second.clone()) }).unwrap();
+    log.append(&WalEntry::Remove { uuid: first.uuid }).unwrap();
+
+    // Only the second item survives a restart.
+    let restored = wal::replay(path).unwrap().messages;
+    assert_eq!(restored, vec![second.clone()]);
+
+    // Compacting the log preserves the surviving items.
+    wal::compact(path, &restored, &[]).unwrap();
+    let restored = wal::replay(path).unwrap().messages;
+    assert_eq!(restored, vec![second]);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn write_ahead_log_compaction() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+    std::env::set_var("ROCKET_QUEUE_WAL_PATH", path);
+    std::env::set_var("ROCKET_QUEUE_WAL_COMPACTION_SIZE_IN_BYTES", "2000");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUE_WAL_PATH");
+    std::env::remove_var("ROCKET_QUEUE_WAL_COMPACTION_SIZE_IN_BYTES");
+
+    // Queue ten items and deliver most of them, so the log is mostly removed items.
+    for i in 0..10 {
+        let res = client.post("/")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "contents": "Item {}" }}"#, i))
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+    }
+    for _ in 0..8 {
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+    assert!(WAL.lock().unwrap().needs_compaction());
+    let size = std::fs::metadata(path).unwrap().len();
+
+    // Compacting only keeps the items that are still queued.
+    wal::compact_queue(time_since_epoch());
+    assert!(!WAL.lock().unwrap().needs_compaction());
+    assert!(std::fs::metadata(path).unwrap().len() < size / 2);
+    let restored: Vec<String> = wal::replay(path).unwrap().messages.into_iter().map(|m| m.contents).collect();
+    assert_eq!(restored, vec!["Item 8", "Item 9"]);
+
+    // The compacted log is still written to.
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(wal::replay(path).unwrap().messages.len(), 1);
+
+    *WAL.lock().unwrap() = WriteAheadLog::default();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn disk_store_pops_by_priority() {
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let directory = directory.to_str().unwrap();
+    let mut store = DiskStore::create(directory, 1024).unwrap();
+
+    for (contents, priority, size_in_bytes) in &[("Item one", 10, 100), ("Item two", 5, 100), ("Item three", 20, 500)] {
+        let message = InternalMessage {
+            contents: contents.to_string(),
+            priority: *priority,
+            size_in_bytes: *size_in_bytes,
+            uuid: Uuid::new_v4(),
+            ..Default::default()
+        };
+        store.push(message).unwrap();
+    }
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.bytes(), 700);
+    assert!(store.has_room(324));
+    assert!(!store.has_room(325));
+    assert_eq!(store.peek().unwrap().contents, "Item three");
+    assert_eq!(store.position(10), (1, 1));
+    assert_eq!(store.position(30), (0, 0));
+
+    // Nothing is popped if the highest priority item doesn't fit.
+    assert!(store.pop_if_fits(499).is_none());
+
+    // Items are popped highest priority first.
+    assert_eq!(store.pop_if_fits(500).unwrap().unwrap().contents, "Item three");
+    assert_eq!(store.pop().unwrap().contents, "Item one");
+    assert_eq!(store.pop().unwrap().contents, "Item two");
+    assert!(store.pop().is_none());
+    assert_eq!(store.bytes(), 0);
+
+    // Segments are deleted once they have been emptied.
+    assert_eq!(std::fs::read_dir(directory).unwrap().count(), 0);
+    std::fs::remove_dir(directory).unwrap();
+}
+
+#[test]
+fn disk_store_survives_restart() {
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let directory = directory.to_str().unwrap();
+
+    let mut uuids = Vec::new();
+    {
+        let mut store = DiskStore::open(directory, 1024).unwrap();
+        for (contents, priority) in &[("Item one", 10), ("Item two", 5), ("Item three", 20)] {
+            let message = InternalMessage {
+                contents: contents.to_string(),
+                priority: *priority,
+                size_in_bytes: contents.len(),
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            };
+            uuids.push(message.uuid);
+            store.push(message).unwrap();
+        }
+        assert_eq!(store.remove(&uuids[0]).unwrap().contents, "Item one");
+    }
+
+    // Removed items stay removed after reopening the store.
+    let mut store = DiskStore::open(directory, 1024).unwrap();
+    assert_eq!(store.len(), 2);
+    assert!(store.remove(&uuids[0]).is_none());
+    assert_eq!(store.pop().unwrap().contents, "Item three");
+    assert_eq!(store.pop().unwrap().contents, "Item two");
+    assert!(store.pop().is_none());
+    drop(store);
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn disk_store_survives_crash() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let directory = directory.to_str().unwrap();
+    let wal_path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let wal_path = wal_path.to_str().unwrap();
+    std::env::set_var("ROCKET_QUEUE_BACKEND", "disk");
+    std::env::set_var("ROCKET_QUEUE_DISK_PATH", directory);
+    std::env::set_var("ROCKET_QUEUE_WAL_PATH", wal_path);
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+
+    let mut uuids = Vec::new();
+    {
+        let client = Client::new(rocket(time_since_epoch())).unwrap();
+        for body in &[r#"{ "contents": "Item one" }"#, r#"{ "contents": "Item two", "delay_ms": 60000 }"#, r#"{ "contents": "Item three" }"#] {
+            let mut res = client.post("/").header(ContentType::JSON).body(*body).dispatch();
+            assert_eq!(res.status(), Status::Accepted);
+            let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            uuids.push(body["data"]["uuid"].as_str().unwrap().to_string());
+        }
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], "Item one");
+    }
+
+    // Crash without a shutdown, losing the lease and the scheduled message from memory.
+    *LEASES.lock().unwrap() = Leases::default();
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    wal::restore(time_since_epoch());
+    for name in &["ROCKET_QUEUE_BACKEND", "ROCKET_QUEUE_DISK_PATH", "ROCKET_QUEUE_WAL_PATH", "ROCKET_LEASE_MODE"] {
+        std::env::remove_var(name);
+    }
+
+    // The message still on disk is only restored once, the others come from the log.
+    assert_eq!(QUEUE.lock().unwrap().len(), 3);
+    for contents in &["Item one", "Item three"] {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], *contents);
+    }
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let mut res = client.get(format!("/messages/{}", uuids[1])).header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["state"], "queued");
+
+    *WAL.lock().unwrap() = WriteAheadLog::default();
+    *LEASES.lock().unwrap() = Leases::default();
+    std::fs::remove_file(wal_path).unwrap();
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let path = std::env::temp_dir().join(format!("rqueue-test-{}.snapshot", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+
+    // Nothing to restore if there's no snapshot.
+    assert!(shutdown::read_snapshot(path).unwrap().is_none());
+
+    let message = InternalMessage {
+        contents: "Item one".to_string(),
+        priority: 20,
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    };
+    let snapshot = Snapshot {
+        queue_requests: 3,
+        proxy_requests: 2,
+        queued: 2,
+        proxied: 1,
+        messages: vec![message.clone()],
+        ..Default::default()
+    };
+    shutdown::write_snapshot(path, &snapshot).unwrap();
+
+    let restored = shutdown::read_snapshot(path).unwrap().unwrap();
+    assert_eq!(restored.queue_requests, 3);
+    assert_eq!(restored.proxy_requests, 2);
+    assert_eq!(restored.queued, 2);
+    assert_eq!(restored.proxied, 1);
+    assert_eq!(restored.messages, vec![message]);
+    assert_eq!(restored.messages[0].priority, 20);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn snapshot_and_log_restore_once() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let wal_path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let wal_path = wal_path.to_str().unwrap();
+    let snapshot_path = std::env::temp_dir().join(format!("rqueue-test-{}.snapshot", Uuid::new_v4()));
+    let snapshot_path = snapshot_path.to_str().unwrap();
+
+    // Stopping between saving the snapshot and clearing the log leaves messages in both.
+    let messages: Vec<InternalMessage> = ["Item one", "Item two"].iter()
+        .map(|contents| InternalMessage {
+            contents: contents.to_string(),
+            size_in_bytes: contents.len(),
+            uuid: Uuid::new_v4(),
+            queue: store::DEFAULT_QUEUE.to_string(),
+            ..Default::default()
+        })
+        .collect();
+    let mut log = WriteAheadLog::open(wal_path).unwrap();
+    log.append(&WalEntry::Push { message: Box::new(messages[0].clone()) }).unwrap();
+    drop(log);
+    let snapshot = Snapshot {
+        messages: messages.clone(),
+        ..Default::default()
+    };
+    shutdown::write_snapshot(snapshot_path, &snapshot).unwrap();
+
+    std::env::set_var("ROCKET_QUEUE_WAL_PATH", wal_path);
+    std::env::set_var("ROCKET_QUEUE_SNAPSHOT_PATH", snapshot_path);
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUE_WAL_PATH");
+    std::env::remove_var("ROCKET_QUEUE_SNAPSHOT_PATH");
+    wal::restore(time_since_epoch());
+    shutdown::restore(time_since_epoch());
+
+    // Each message is only queued once, and both are in the log for the next restart.
+    assert_eq!(QUEUE.lock().unwrap().len(), 2);
+    assert_eq!(wal::replay(wal_path).unwrap().messages, messages);
+    for contents in &["Item one", "Item two"] {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], *contents);
+    }
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    *WAL.lock().unwrap() = WriteAheadLog::default();
+    std::fs::remove_file(wal_path).unwrap();
+    assert!(shutdown::read_snapshot(snapshot_path).unwrap().is_none());
+}
+
+#[test]
+fn reject_while_shutting_down() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    shutdown::SHUTTING_DOWN.store(true, Ordering::Relaxed);
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    shutdown::SHUTTING_DOWN.store(false, Ordering::Relaxed);
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+    let body = res.body_string().unwrap();
+    assert!(body.contains("shutting down"));
+
+    // Nothing was queued.
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn named_queues() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_QUEUES", "{alerts={memory_limit_in_bytes=1024}}");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUES");
+
+    // Unconfigured queues can't be used.
+    let mut res = client.post("/queues/unknown")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert!(res.body_string().unwrap().contains("Unknown queue."));
+    let res = client.get("/queues/unknown").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Add an item to the named queue, and another to the default queue.
+    let res = client.post("/queues/alerts")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item two", "priority": 200 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Each queue only returns its own items.
+    let mut res = client.get("/queues/alerts").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body = res.body_string().unwrap();
+    assert!(body.contains("Item one"));
+    assert!(body.contains("alerts"));
+    let res = client.get("/queues/alerts").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    let mut res = client.get("/queues/default").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res.body_string().unwrap().contains("Item two"));
+
+    // Named queues have their own memory limit.
+    let res = client.post("/queues/alerts")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "{}" }}"#, "x".repeat(1024)))
+        .dispatch();
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+}
+
+#[test]
+fn leases_expire() {
+    let mut leases = Leases::default();
+    let message = InternalMessage {
+        contents: "Item one".to_string(),
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    };
+    let receipt = leases.lease(message.clone(), 30_000);
+
+    // Nothing expires before the visibility timeout.
+    let now = time_since_epoch().as_millis();
+    assert!(leases.take_expired(now).is_empty());
+
+    // The message is returned once the lease expires, and the receipt is no longer valid.
+    assert_eq!(leases.take_expired(now + 30_000), vec![message]);
+    assert!(leases.take(&receipt).is_none());
+}
+
+#[test]
+fn lease_ack_and_nack() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_LEASE_MODE");
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Leasing the message hides it from the queue.
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // A nack returns the message to the queue, invalidating the receipt.
+    let res = client.post(format!("/nack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.post(format!("/ack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // The message can then be leased again and acknowledged.
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["contents"], "Item one");
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    let res = client.post(format!("/ack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // Once acknowledged, the message is gone.
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.post("/ack/not-a-receipt").dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn dead_letter_after_max_delivery_attempts() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+    std::env::set_var("ROCKET_MAX_DELIVERY_ATTEMPTS", "1");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_LEASE_MODE");
+    std::env::remove_var("ROCKET_MAX_DELIVERY_ATTEMPTS");
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Rejecting the only delivery attempt moves the message to the dead-letter queue.
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let uuid = body["data"]["uuid"].as_str().unwrap().to_string();
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    let res = client.post(format!("/nack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Dead letters can be listed, without their contents, and inspected.
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    assert_eq!(body["data"][0]["uuid"], uuid.as_str());
+    assert_eq!(body["data"][0]["delivery_attempts"], 1);
+    assert_eq!(body["data"][0]["error"], "rejected by consumer");
+    assert!(body["data"][0]["contents"].is_null());
+    let mut res = client.get(format!("/dead-letters/{}", uuid)).header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["contents"], "Item one");
+
+    // Requeued messages can be delivered again.
+    let res = client.post(format!("/dead-letters/{}/requeue", uuid)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["uuid"], uuid.as_str());
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    let res = client.post(format!("/nack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // Purged messages are gone for good.
+    let res = client.delete(format!("/dead-letters/{}", uuid)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.get(format!("/dead-letters/{}", uuid)).header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.post(format!("/dead-letters/{}/requeue", uuid)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let mut res = client.delete("/dead-letters").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res.body_string().unwrap().contains(r#""purged":0"#));
+}
+
+#[test]
+fn dead_letters_survive_crash() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+    std::env::set_var("ROCKET_QUEUE_WAL_PATH", path);
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+    std::env::set_var("ROCKET_MAX_DELIVERY_ATTEMPTS", "1");
+
+    // Two messages are rejected and moved to the dead-letter queue.
+    let mut uuids = Vec::new();
+    {
+        let client = Client::new(rocket(time_since_epoch())).unwrap();
+        for contents in &["Item one", "Item two"] {
+            let res = client.post("/")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "contents": "{}" }}"#, contents))
+                .dispatch();
+            assert_eq!(res.status(), Status::Accepted);
+            let mut res = client.get("/").header(ContentType::JSON).dispatch();
+            let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            uuids.push(body["data"]["uuid"].as_str().unwrap().to_string());
+            let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+            let res = client.post(format!("/nack/{}", receipt)).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+        }
+        assert_eq!(wal::replay(path).unwrap().dead_letters.len(), 2);
+
+        // Compacting keeps them, purging removes them from the log.
+        wal::compact_queue(time_since_epoch());
+        let res = client.delete(format!("/dead-letters/{}", uuids[1])).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    // After a crash the remaining dead letter is restored with its error, and can be requeued.
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    wal::restore(time_since_epoch());
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    assert_eq!(body["data"][0]["uuid"], uuids[0].as_str());
+    assert_eq!(body["data"][0]["error"], "rejected by consumer");
+    let res = client.post(format!("/dead-letters/{}/requeue", uuids[0])).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // Once requeued, it is restored to the queue instead.
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    wal::restore(time_since_epoch());
+    for name in &["ROCKET_QUEUE_WAL_PATH", "ROCKET_LEASE_MODE", "ROCKET_MAX_DELIVERY_ATTEMPTS"] {
+        std::env::remove_var(name);
+    }
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains(r#""count":0"#));
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["uuid"], uuids[0].as_str());
+
+    *WAL.lock().unwrap() = WriteAheadLog::default();
+    *LEASES.lock().unwrap() = Leases::default();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn dead_letter_failed_notifications() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_MAX_DELIVERY_ATTEMPTS", "2");
+    std::env::set_var("ROCKET_RETRY_BACKOFF_BASE_MS", "1");
+    std::env::set_var("ROCKET_RETRY_BACKOFF_MAX_MS", "1");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_MAX_DELIVERY_ATTEMPTS");
+    std::env::remove_var("ROCKET_RETRY_BACKOFF_BASE_MS");
+    std::env::remove_var("ROCKET_RETRY_BACKOFF_MAX_MS");
+    // Nothing is listening for SMTP here, so every email fails to send.
+    *NOTIFY_CONFIG.lock().unwrap() = NotifyConfig {
+        mail_from_address: "rqueue@example.com".to_string(),
+        mail_to_address: "alerts@example.com".to_string(),
+        smtp_server: "127.0.0.1".to_string(),
+        ..Default::default()
+    };
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Deliver the message the way the notify thread does.
+    let notify_next = || {
+        std::thread::sleep(Duration::from_millis(10));
+        let mut message = QUEUE.lock().unwrap().pop().expect("message in queue");
+        message.delivery_attempts += 1;
+        notify::notify(message, time_since_epoch());
+    };
+
+    // The first failure returns the message to the queue, the second uses up its attempts.
+    notify_next();
+    let mut res = client.get("/messages").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    assert_eq!(body["data"][0]["delivery_attempts"], 1);
+    notify_next();
+    assert!(QUEUE.lock().unwrap().pop().is_none());
+
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    assert_eq!(body["data"][0]["delivery_attempts"], 2);
+    assert!(body["data"][0]["error"].as_str().unwrap().starts_with("failed to send email"));
+    *NOTIFY_CONFIG.lock().unwrap() = NotifyConfig::default();
+}
+
+#[test]
+fn parse_retry_after() {
+    assert_eq!(proxy::retry_after("120"), Some(120));
+    assert_eq!(proxy::retry_after(" 0 "), Some(0));
+    let later = std::time::SystemTime::now() + std::time::Duration::from_secs(300);
+    let seconds = proxy::retry_after(&httpdate::fmt_http_date(later)).unwrap();
+    assert!(seconds > 290 && seconds <= 300);
+    // Dates in the past mean retry immediately.
+    assert_eq!(proxy::retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(0));
+    assert_eq!(proxy::retry_after("soon"), None);
+}
+
+#[test]
+fn retry_backoff() {
+    let config = RetryConfig { base: 1000, max: 10_000, jitter: 0.5 };
+    // Without any randomness the delay doubles after each failure, up to the maximum.
+    assert_eq!(config.backoff(1, 0.0), 1000);
+    assert_eq!(config.backoff(2, 0.0), 2000);
+    assert_eq!(config.backoff(4, 0.0), 8000);
+    assert_eq!(config.backoff(5, 0.0), 10_000);
+    assert_eq!(config.backoff(500, 0.0), 10_000);
+    // Jitter removes up to half of the delay.
+    assert_eq!(config.backoff(2, 0.5), 1500);
+    assert_eq!(config.backoff(5, 1.0), 5000);
+}
+
+#[test]
+fn upstream_strategies() {
+    let servers = || vec![Upstream::new("a", 3), Upstream::new("b", 1), Upstream::new("c", 1)];
+    let choose = |upstreams: &mut Upstreams, count: usize| -> String {
+        (0..count).map(|_| {
+            let index = upstreams.choose(0).unwrap();
+            upstreams.get(index).url.clone()
+        }).collect()
+    };
+
+    let mut round_robin = Upstreams::new(Strategy::RoundRobin, servers());
+    assert_eq!(choose(&mut round_robin, 6), "abcabc");
+
+    // Each upstream is chosen in proportion to its weight, spread out evenly.
+    let mut weighted = Upstreams::new(Strategy::Weighted, servers());
+    assert_eq!(choose(&mut weighted, 10), "abacaabaca");
+
+    let mut failover = Upstreams::new(Strategy::Failover, servers());
+    assert_eq!(choose(&mut failover, 3), "aaa");
+}
+
+#[test]
+fn upstream_ejection() {
+    let mut upstreams = Upstreams::new(Strategy::Failover, vec![Upstream::new("a", 1), Upstream::new("b", 1)]);
+    upstreams.failure_limit = 2;
+    upstreams.ejection_time = 1000;
+
+    // Only consecutive failures count towards ejection.
+    assert!(!upstreams.failed(0, 0));
+    upstreams.delivered(0);
+    assert!(!upstreams.failed(0, 0));
+    assert_eq!(upstreams.choose(0), Some(0));
+    assert!(upstreams.failed(0, 0));
+    assert_eq!(upstreams.choose(0), Some(1));
+
+    // Once every upstream is unavailable, nothing is chosen until one returns.
+    upstreams.retry_after(1, 500, 0);
+    assert!(!upstreams.is_available(100));
+    assert_eq!(upstreams.choose(100), None);
+    assert_eq!(upstreams.wait_time(100), 400);
+    assert_eq!(upstreams.choose(500), Some(1));
+
+    // An upstream that returns after being ejected is ejected again by its next failure.
+    assert_eq!(upstreams.choose(1000), Some(0));
+    assert!(upstreams.failed(0, 1000));
+    assert_eq!(upstreams.choose(1000), Some(1));
+    upstreams.delivered(0);
+    assert_eq!(upstreams.choose(2000), Some(0));
+
+    let a = upstreams.get(0);
+    assert_eq!((a.delivered, a.failed, a.ejections), (2, 4, 2));
+}
+
+#[test]
+fn scheduled_store_holds_back_messages() {
+    let mut store = ScheduledStore::new(Box::new(MemoryStore::new(1000, None)));
+    let later = InternalMessage {
+        contents: "Later".to_string(),
+        priority: 20,
+        size_in_bytes: 400,
+        uuid: Uuid::new_v4(),
+        not_before: time_since_epoch().as_millis() + 60_000,
+        ..Default::default()
+    };
+    let later_uuid = later.uuid;
+    store.push(later).unwrap();
+    store.push(InternalMessage {
+        contents: "Now".to_string(),
+        priority: 5,
+        size_in_bytes: 400,
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    }).unwrap();
+
+    // Messages that aren't eligible yet still count against the limit.
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.bytes(), 800);
+    assert!(store.has_room(200));
+    assert!(!store.has_room(201));
+
+    // The lower priority message is delivered first, as the other must wait.
+    assert_eq!(store.pop().unwrap().contents, "Now");
+    assert!(store.pop().is_none());
+    assert_eq!(store.len(), 1);
+
+    // Waiting messages can still be removed or drained.
+    assert_eq!(store.remove(&later_uuid).unwrap().contents, "Later");
+    assert_eq!(store.bytes(), 0);
+    store.push(InternalMessage {
+        size_in_bytes: 100,
+        uuid: Uuid::new_v4(),
+        not_before: time_since_epoch().as_millis() + 60_000,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(store.drain().len(), 1);
+    assert_eq!(store.len(), 0);
+}
+
+#[test]
+fn delayed_delivery() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_QUEUE_MEMORY_LIMIT_IN_BYTES", "3000");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUE_MEMORY_LIMIT_IN_BYTES");
+
+    // Messages scheduled for the future aren't delivered yet.
+    let contents = "x".repeat(2000);
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "{}", "delay_ms": 60000 }}"#, contents))
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // But they still count against the memory limit.
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "{}" }}"#, contents))
+        .dispatch();
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+
+    // Messages scheduled for the past are delivered right away.
+    let deliver_at = time_since_epoch().as_millis() - 1000;
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "Item one", "deliver_at": {} }}"#, deliver_at))
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res.body_string().unwrap().contains("Item one"));
+
+    // Only one way of scheduling can be used at a time.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "Item two", "deliver_at": {}, "delay_ms": 10 }}"#, deliver_at))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+    assert!(res.body_string().unwrap().contains("not both"));
+}
+
+#[test]
+fn messages_expire() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one", "ttl_ms": 1 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item two", "ttl_ms": 60000 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Only the expired message is removed, and it is dropped by default.
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(expiry::sweep(time_since_epoch()), 1);
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains("Item two"));
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains(r#""count":0"#));
+
+    // A message that expired since the last sweep is skipped when popped.
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item five", "ttl_ms": 1, "priority": 10 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item six" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let expired = COUNTERS.lock().unwrap().expired.load(Ordering::Relaxed);
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains("Item six"));
+    assert_eq!(COUNTERS.lock().unwrap().expired.load(Ordering::Relaxed), expired + 1);
+    assert_eq!(QUEUE.lock().unwrap().len(), 0);
+    assert_eq!(expiry::sweep(time_since_epoch()), 0);
+
+    // Expiry can only be set one way at a time.
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item three", "ttl_ms": 1, "expires_at": 1 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+
+    // Expired messages can be kept in the dead-letter queue instead.
+    std::env::set_var("ROCKET_EXPIRED_MESSAGE_ACTION", "dead-letter");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_EXPIRED_MESSAGE_ACTION");
+    let expires_at = time_since_epoch().as_millis() - 1;
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{ "contents": "Item four", "expires_at": {} }}"#, expires_at))
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    assert_eq!(expiry::sweep(time_since_epoch()), 1);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let mut res = client.get("/dead-letters").header(ContentType::JSON).dispatch();
+    let body = res.body_string().unwrap();
+    assert!(body.contains(r#""count":1"#));
+    assert!(body.contains(r#""error":"expired""#));
+    expiry::DEAD_LETTER_EXPIRED.store(false, Ordering::Relaxed);
+}
+
+#[test]
+fn priority_aging() {
+    let aging = AgingConfig { interval: 1000, ceiling: 20 };
+    // One priority is gained for each full interval waited, up to the ceiling.
+    assert_eq!(aging.priority(10, 10, 0, 999), 10);
+    assert_eq!(aging.priority(10, 10, 0, 1000), 11);
+    assert_eq!(aging.priority(10, 10, 5000, 8500), 13);
+    assert_eq!(aging.priority(10, 10, 0, 60_000), 20);
+    // Priority is never lowered.
+    assert_eq!(aging.priority(10, 50, 0, 1000), 50);
+    assert_eq!(aging.priority(30, 30, 0, 60_000), 30);
+    assert_eq!(AgingConfig::default().priority(10, 10, 0, 60_000), 10);
+
+    // Old, low priority messages overtake newer, higher priority messages.
+    let now = time_since_epoch().as_millis();
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let overflow = DiskStore::create(directory.to_str().unwrap(), 1024).unwrap();
+    let mut store = MemoryStore::new(1000, Some(overflow));
+    for (contents, priority, size_in_bytes, arrived) in &[("Old", 1, 600, now - 15_000), ("New", 12, 600, now), ("Older", 0, 200, now - 30_000)] {
+        store.push(InternalMessage {
+            contents: contents.to_string(),
+            priority: *priority,
+            original_priority: *priority,
+            size_in_bytes: *size_in_bytes,
+            arrived: *arrived,
+            uuid: Uuid::new_v4(),
+            ..Default::default()
+        }).unwrap();
+    }
+    // Messages that didn't fit in memory are aged on disk.
+    assert_eq!(store.overflow().0, 2);
+    assert_eq!(store.age(now, &aging), 2);
+    assert_eq!(store.age(now, &aging), 0);
+
+    let message = store.pop().unwrap();
+    assert_eq!((message.contents.as_str(), message.priority, message.original_priority), ("Older", 20, 0));
+    let message = store.pop().unwrap();
+    assert_eq!((message.contents.as_str(), message.priority, message.original_priority), ("Old", 16, 1));
+    let message = store.pop().unwrap();
+    assert_eq!((message.contents.as_str(), message.priority, message.original_priority), ("New", 12, 12));
+    std::fs::remove_dir_all(directory).unwrap();
+
+    // Scheduled messages age while they wait to be delivered.
+    let mut store = ScheduledStore::new(Box::new(MemoryStore::new(1000, None)));
+    store.push(InternalMessage {
+        contents: "Scheduled".to_string(),
+        priority: 5,
+        original_priority: 5,
+        arrived: now - 3000,
+        not_before: now + 60_000,
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(store.age(now, &aging), 1);
+    assert_eq!(store.list(0, 10, 0)[0].priority, 8);
+}
+
+// Randomly push and pop `count` messages with a handful of priorities, checking that
+// every message comes out highest priority first, and first in first out within the
+// same priority.
+fn check_fifo_order(store: &mut dyn QueueStore, count: usize, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut expected: BTreeMap<(Reverse<u8>, u64), Uuid> = BTreeMap::new();
+    let mut pushed = 0;
+    while pushed < count || !expected.is_empty() {
+        if pushed < count && (expected.is_empty() || rng.gen_bool(0.7)) {
+            let message = InternalMessage {
+                priority: rng.gen_range(0..8),
+                size_in_bytes: rng.gen_range(100..300),
+                uuid: Uuid::new_v4(),
+                sequence: store::next_sequence(),
+                ..Default::default()
+            };
+            expected.insert((Reverse(message.priority), message.sequence), message.uuid);
+            store.push(message).unwrap();
+            pushed += 1;
+        }
+        else {
+            let next = *expected.keys().next().unwrap();
+            let message = store.pop().unwrap();
+            assert_eq!((message.priority, message.sequence), (next.0.0, next.1));
+            assert_eq!(message.uuid, expected.remove(&next).unwrap());
+        }
+    }
+    assert!(store.pop().is_none());
+}
+
+#[test]
+fn fifo_within_priority() {
+    check_fifo_order(&mut MemoryStore::new(usize::MAX, None), 5000, 1);
+    check_fifo_order(&mut ScheduledStore::new(Box::new(MemoryStore::new(usize::MAX, None))), 5000, 2);
+
+    // Messages keep their order when they are moved to and from disk.
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let directory = directory.to_str().unwrap();
+    check_fifo_order(&mut DiskStore::create(directory, usize::MAX).unwrap(), 2000, 3);
+    let overflow = DiskStore::create(directory, usize::MAX).unwrap();
+    check_fifo_order(&mut MemoryStore::new(20_000, Some(overflow)), 2000, 4);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn deduplicate_messages() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_DEDUP_WINDOW", "60");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_DEDUP_WINDOW");
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    // Resending a queued message returns the uuid of the original.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one", "priority": 50 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["status"], "duplicate");
+    let uuid = body["data"]["uuid"].as_str().unwrap().to_string();
+
+    let res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item two" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["uuid"], uuid.as_str());
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Delivered messages are remembered for the window.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert!(res.body_string().unwrap().contains(&uuid));
+}
+
+#[test]
+fn deduplication_window() {
+    let mut dedup = Dedup::default();
+    dedup.window = 1000;
+    let message = InternalMessage {
+        sha256: "abc".to_string(),
+        queue: "default".to_string(),
+        uuid: Uuid::new_v4(),
+        ..Default::default()
+    };
+    dedup.queued(&message);
+    assert_eq!(dedup.find("default", "abc", 0), Some(message.uuid));
+    // Messages are only duplicates within the same queue.
+    assert_eq!(dedup.find("alerts", "abc", 0), None);
+
+    // Delivered messages are forgotten once the window passes.
+    dedup.removed(&message.uuid, true, 5000);
+    assert_eq!(dedup.find("default", "abc", 5999), Some(message.uuid));
+    assert_eq!(dedup.find("default", "abc", 6000), None);
+
+    // Messages that weren't delivered are forgotten right away.
+    dedup.queued(&message);
+    dedup.removed(&message.uuid, false, 7000);
+    assert_eq!(dedup.find("default", "abc", 7000), None);
+}
+
+#[test]
+fn idempotency_keys() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", "one"))
+        .body(r#"{ "contents": "Item one" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    let original = res.body_string().unwrap();
+    assert!(original.contains("uuid"));
+
+    // Repeating the key returns the original response, even with different contents.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", "one"))
+        .body(r#"{ "contents": "Item two" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    assert_eq!(res.body_string().unwrap(), original);
+
+    // The key can also be sent in the message.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one", "idempotency_key": "one" }"#)
+        .dispatch();
+    assert_eq!(res.body_string().unwrap(), original);
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item three", "idempotency_key": "three" }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Accepted);
+    assert_ne!(res.body_string().unwrap(), original);
+
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains("Item one"));
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    assert!(res.body_string().unwrap().contains("Item three"));
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn idempotency_keys_per_queue() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    // Exactly enough memory for one message with 500 bytes of contents.
+    let limit = std::mem::size_of::<InternalMessage>() + 500 + 64 + "alerts".len();
+    std::env::set_var("ROCKET_QUEUES", format!("{{alerts={{memory_limit_in_bytes={}}}}}", limit));
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUES");
+
+    let post = |url: &str, contents: &str| -> (Status, serde_json::Value) {
+        let mut res = client.post(url)
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", "one"))
+            .body(serde_json::json!({ "contents": contents }).to_string())
+            .dispatch();
+        (res.status(), serde_json::from_str(&res.body_string().unwrap()).unwrap())
+    };
+
+    // The same key can be used with different queues.
+    let (status, alert) = post("/queues/alerts", "Item one");
+    assert_eq!(status, Status::Accepted);
+    let (status, body) = post("/", "Item one");
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(body["data"]["queue"], "default");
+    assert_ne!(body["data"]["uuid"], alert["data"]["uuid"]);
+    let (_, body) = post("/queues/alerts", "Item two");
+    assert_eq!(body, alert);
+
+    // Only the queue the key was used with has the message cancelled.
+    let res = client.delete("/messages/idempotency-key/one?queue=alerts").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.get("/queues/alerts").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    // The remembered response still uses the queue's memory.
+    let mut res = client.post("/queues/alerts")
+        .header(ContentType::JSON)
+        .body(serde_json::json!({ "contents": "x".repeat(500) }).to_string())
+        .dispatch();
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+    assert!(res.body_string().unwrap().contains("insufficient memory"));
+}
+
+#[test]
+fn post_returns_position() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    let mut uuids = Vec::new();
+    for (priority, position, depth) in &[(10, 1, 1), (10, 2, 2), (50, 1, 1), (10, 4, 3)] {
+        let mut res = client.post("/")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "contents": "Item", "priority": {} }}"#, priority))
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["priority"], *priority);
+        assert_eq!(body["data"]["position"], *position);
+        assert_eq!(body["data"]["depth"], *depth);
+        assert_eq!(body["data"]["queue"], "default");
+        assert_eq!(body["data"]["sha256"].as_str().unwrap().len(), 64);
+        uuids.push(body["data"]["uuid"].as_str().unwrap().to_string());
+    }
+
+    // The uuid identifies the message when it is delivered.
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["uuid"], uuids[2].as_str());
+}
+
+#[test]
+fn message_status() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+    std::env::set_var("ROCKET_MAX_DELIVERY_ATTEMPTS", "2");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_LEASE_MODE");
+    std::env::remove_var("ROCKET_MAX_DELIVERY_ATTEMPTS");
+
+    let status = |uuid: &str| -> serde_json::Value {
+        let mut res = client.get(format!("/messages/{}", uuid)).header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        body["data"].clone()
+    };
+    let lease = || -> String {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        body["data"]["receipt"].as_str().unwrap().to_string()
+    };
+
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item one", "priority": 20 }"#)
+        .dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let uuid = body["data"]["uuid"].as_str().unwrap().to_string();
+    let data = status(&uuid);
+    assert_eq!(data["state"], "queued");
+    assert_eq!(data["priority"], 20);
+    assert_eq!(data["original_priority"], 20);
+    assert_eq!(data["delivery_attempts"], 0);
+    assert!(data["arrived"].as_u64().unwrap() > 0);
+    assert!(data["last_error"].is_null());
+
+    // Failed deliveries are counted, along with why they failed.
+    let receipt = lease();
+    assert_eq!(status(&uuid)["state"], "leased");
+    client.post(format!("/nack/{}", receipt)).dispatch();
+    let data = status(&uuid);
+    assert_eq!(data["state"], "queued");
+    assert_eq!(data["delivery_attempts"], 1);
+    assert_eq!(data["last_error"], "rejected by consumer");
+
+    let receipt = lease();
+    client.post(format!("/nack/{}", receipt)).dispatch();
+    let data = status(&uuid);
+    assert_eq!(data["state"], "dead_lettered");
+    assert_eq!(data["delivery_attempts"], 2);
+    assert!(data["finished"].as_u64().is_some());
+
+    // Delivered messages are remembered after they leave the queue.
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .body(r#"{ "contents": "Item two" }"#)
+        .dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let uuid = body["data"]["uuid"].as_str().unwrap().to_string();
+    let receipt = lease();
+    client.post(format!("/ack/{}", receipt)).dispatch();
+    assert_eq!(status(&uuid)["state"], "delivered");
+
+    let res = client.get(format!("/messages/{}", Uuid::new_v4())).header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.get("/messages/nothing").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn cancel_messages() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_LEASE_MODE", "true");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_LEASE_MODE");
+
+    let post = |body: &str, key: Option<&str>| -> serde_json::Value {
+        let mut req = client.post("/").header(ContentType::JSON).body(body);
+        if let Some(key) = key {
+            req.add_header(Header::new("Idempotency-Key", key.to_string()));
+        }
+        let mut res = req.dispatch();
+        serde_json::from_str(&res.body_string().unwrap()).unwrap()
+    };
+    // Counters are shared with other tests, so compare against where they started.
+    let counters = || -> (usize, usize, usize) {
+        let counters = COUNTERS.lock().unwrap();
+        (
+            counters.in_queue.load(Ordering::Relaxed),
+            counters.bytes.load(Ordering::Relaxed),
+            counters.leased.load(Ordering::Relaxed),
+        )
+    };
+    let (in_queue, bytes, leased) = counters();
+
+    let first = post(r#"{ "contents": "Item one" }"#, None);
+    let uuid = first["data"]["uuid"].as_str().unwrap().to_string();
+    post(r#"{ "contents": "Item two" }"#, None);
+    post(r#"{ "contents": "Item two" }"#, None);
+    post(r#"{ "contents": "Item three" }"#, Some("three"));
+    assert_eq!(counters().0, in_queue + 4);
+
+    // Cancel by uuid.
+    let mut res = client.delete(format!("/messages/{}", uuid)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"][0], uuid.as_str());
+    let mut res = client.get(format!("/messages/{}", uuid)).header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["state"], "cancelled");
+    let res = client.delete(format!("/messages/{}", uuid)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Cancel every copy with the same sha256.
+    let sha256 = format!("{:x}", Sha256::digest(b"Item two"));
+    let mut res = client.delete(format!("/messages/sha256/{}", sha256)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 2);
+
+    // Cancel by idempotency key.
+    let res = client.delete("/messages/idempotency-key/three").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.delete("/messages/idempotency-key/three").dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    assert_eq!(counters(), (in_queue, bytes, leased));
+
+    // Leased messages can be cancelled, delivered ones can't.
+    let uuid = post(r#"{ "contents": "Item four" }"#, None)["data"]["uuid"].as_str().unwrap().to_string();
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    let res = client.delete(format!("/messages/{}", uuid)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client.post(format!("/ack/{}", receipt)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert_eq!(counters(), (in_queue, bytes, leased));
+
+    let uuid = post(r#"{ "contents": "Item five" }"#, None)["data"]["uuid"].as_str().unwrap().to_string();
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    let receipt = body["data"]["receipt"].as_str().unwrap().to_string();
+    client.post(format!("/ack/{}", receipt)).dispatch();
+    let mut res = client.delete(format!("/messages/{}", uuid)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["state"], "delivered");
+}
+
+#[test]
+fn cancel_by_sha256_per_queue() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_QUEUES", "{alerts={memory_limit_in_bytes=100000}}");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_QUEUES");
+
+    for url in &["/", "/queues/alerts"] {
+        let res = client.post(*url)
+            .header(ContentType::JSON)
+            .body(r#"{ "contents": "Item one" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Accepted);
+    }
+
+    // Only the messages in the given queue are cancelled.
+    let sha256 = format!("{:x}", Sha256::digest(b"Item one"));
+    let mut res = client.delete(format!("/messages/sha256/{}?queue=alerts", sha256)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    let res = client.get("/queues/alerts").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.delete(format!("/messages/sha256/{}?queue=alerts", sha256)).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Without a queue, only the default queue is searched.
+    let mut res = client.delete(format!("/messages/sha256/{}", sha256)).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 1);
+    let res = client.get("/").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
+
+#[test]
+fn reprioritize_messages() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    let mut uuids = Vec::new();
+    for (contents, priority) in &[("Item one", 10), ("Item two", 20), ("Item three", 30)] {
+        let mut res = client.post("/")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "contents": "{}", "priority": {} }}"#, contents, priority))
+            .dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        uuids.push(body["data"]["uuid"].as_str().unwrap().to_string());
+    }
+
+    // Promote the lowest priority message to the front of the queue.
+    let mut res = client.patch(format!("/messages/{}", uuids[0]))
+        .header(ContentType::JSON)
+        .body(r#"{ "priority": 200 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["priority"], 200);
+    assert_eq!(body["data"]["previous_priority"], 10);
+    assert_eq!(body["data"]["original_priority"], 10);
+
+    // Demote the highest.
+    let res = client.patch(format!("/messages/{}", uuids[2]))
+        .header(ContentType::JSON)
+        .body(r#"{ "priority": 1 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+
+    let mut delivered = Vec::new();
+    for _ in 0..3 {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(res.status(), Status::Ok);
+        delivered.push(body["data"]["contents"].as_str().unwrap().to_string());
+        if delivered.len() == 1 {
+            assert_eq!(body["data"]["priority"], 200);
+            assert_eq!(body["data"]["original_priority"], 10);
+        }
+    }
+    assert_eq!(delivered, vec!["Item one", "Item two", "Item three"]);
+
+    // Delivered and unknown messages can't be changed.
+    let mut res = client.patch(format!("/messages/{}", uuids[0]))
+        .header(ContentType::JSON)
+        .body(r#"{ "priority": 50 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["state"], "delivered");
+    let res = client.patch(format!("/messages/{}", Uuid::new_v4()))
+        .header(ContentType::JSON)
+        .body(r#"{ "priority": 50 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Messages overflowed to disk can be changed too.
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let mut store = DiskStore::create(directory.to_str().unwrap(), 1024).unwrap();
+    let mut uuids = Vec::new();
+    for (contents, priority) in &[("Item one", 10), ("Item two", 20)] {
+        let message = InternalMessage {
+            contents: contents.to_string(),
+            priority: *priority,
+            original_priority: *priority,
+            size_in_bytes: 100,
+            uuid: Uuid::new_v4(),
+            ..Default::default()
+        };
+        uuids.push(message.uuid);
+        store.push(message).unwrap();
+    }
+    assert_eq!(store.reprioritize(&uuids[0], 30, 1), Some(10));
+    let message = store.pop().unwrap();
+    assert_eq!(message.contents, "Item one");
+    assert_eq!(message.priority, 30);
+    assert_eq!(message.original_priority, 10);
+    assert_eq!(store.reprioritize(&uuids[0], 30, 1), None);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn reprioritize_survives_restart() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let path = std::env::temp_dir().join(format!("rqueue-test-{}.wal", Uuid::new_v4()));
+    let path = path.to_str().unwrap();
+    std::env::set_var("ROCKET_QUEUE_WAL_PATH", path);
+
+    let mut uuids = Vec::new();
+    {
+        let client = Client::new(rocket(time_since_epoch())).unwrap();
+        for (contents, priority) in &[("Item one", 10), ("Item two", 20)] {
+            let mut res = client.post("/")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "contents": "{}", "priority": {} }}"#, contents, priority))
+                .dispatch();
+            let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+            uuids.push(body["data"]["uuid"].as_str().unwrap().to_string());
+        }
+        let res = client.patch(format!("/messages/{}", uuids[0]))
+            .header(ContentType::JSON)
+            .body(r#"{ "priority": 200 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    // The new priority is restored from the write-ahead log, along with the change.
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    wal::restore(time_since_epoch());
+    std::env::remove_var("ROCKET_QUEUE_WAL_PATH");
+    let mut res = client.get(format!("/messages/{}", uuids[0])).header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["state"], "queued");
+    assert_eq!(body["data"]["priority"], 200);
+    assert_eq!(body["data"]["reprioritized"][0]["from"], 10);
+    assert_eq!(body["data"]["reprioritized"][0]["to"], 200);
+    for contents in &["Item one", "Item two"] {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], *contents);
+    }
+    *WAL.lock().unwrap() = WriteAheadLog::default();
+    std::fs::remove_file(path).unwrap();
+
+    // Changes to messages in a disk store are written to its segments.
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let directory = directory.to_str().unwrap();
+    let mut uuids = Vec::new();
+    {
+        let mut store = DiskStore::open(directory, 1024).unwrap();
+        for (contents, priority) in &[("Item one", 10), ("Item two", 20)] {
+            let message = InternalMessage {
+                contents: contents.to_string(),
+                priority: *priority,
+                original_priority: *priority,
+                size_in_bytes: contents.len(),
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            };
+            uuids.push(message.uuid);
+            store.push(message).unwrap();
+        }
+        assert_eq!(store.reprioritize(&uuids[0], 30, 1), Some(10));
+        assert_eq!(store.len(), 2);
+    }
+    let mut store = DiskStore::open(directory, 1024).unwrap();
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.bytes(), 16);
+    let message = store.pop().unwrap();
+    assert_eq!(message.contents, "Item one");
+    assert_eq!(message.priority, 30);
+    assert_eq!(message.reprioritized, vec![Reprioritized { from: 10, to: 30, changed: 1 }]);
+    assert_eq!(store.pop().unwrap().contents, "Item two");
+    drop(store);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn demoted_messages_stay_demoted() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_PRIORITY_AGING_INTERVAL", "1");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_PRIORITY_AGING_INTERVAL");
+
+    // A message that has waited a minute has aged well past its original priority.
+    let now = time_since_epoch().as_millis();
+    let uuid = Uuid::new_v4();
+    QUEUE.lock().unwrap().push(InternalMessage {
+        contents: "Noisy".to_string(),
+        priority: 10,
+        original_priority: 10,
+        size_in_bytes: 5,
+        arrived: now - 60_000,
+        uuid,
+        queue: store::DEFAULT_QUEUE.to_string(),
+        ..Default::default()
+    }).unwrap();
+    aging::age(time_since_epoch());
+    let mut res = client.get(format!("/messages/{}", uuid)).header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["priority"], 70);
+
+    // Demoting it sticks, aging starts again from the new priority.
+    let res = client.patch(format!("/messages/{}", uuid))
+        .header(ContentType::JSON)
+        .body(r#"{ "priority": 5 }"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    aging::age(time_since_epoch());
+    let mut res = client.get(format!("/messages/{}", uuid)).header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["priority"], 5);
+    assert_eq!(body["data"]["original_priority"], 10);
+
+    // The same goes for messages on disk, which are aged in the index.
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let mut store = DiskStore::create(directory.to_str().unwrap(), 1024).unwrap();
+    let aging = AgingConfig { interval: 1000, ceiling: 255 };
+    let uuid = Uuid::new_v4();
+    store.push(InternalMessage {
+        contents: "Noisy".to_string(),
+        priority: 10,
+        original_priority: 10,
+        size_in_bytes: 5,
+        arrived: now - 60_000,
+        uuid,
+        ..Default::default()
+    }).unwrap();
+    assert_eq!(store.age(now, &aging), 1);
+    assert_eq!(store.reprioritize(&uuid, 5, now), Some(70));
+    assert_eq!(store.age(now + 999, &aging), 0);
+    assert_eq!(store.age(now + 1000, &aging), 1);
+    assert_eq!(store.pop().unwrap().priority, 6);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[test]
+fn batch_queue() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_DEDUP_WINDOW", "60");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_DEDUP_WINDOW");
+
+    let post = |url: &str, body: &str| -> (Status, serde_json::Value) {
+        let mut res = client.post(url).header(ContentType::JSON).body(body).dispatch();
+        (res.status(), serde_json::from_str(&res.body_string().unwrap()).unwrap())
+    };
+    let in_queue = || COUNTERS.lock().unwrap().in_queue.load(Ordering::Relaxed);
+    let started = in_queue();
+
+    // With any invalid message an atomic batch queues nothing.
+    let batch = r#"[
+        { "contents": "Item one", "priority": 10 },
+        { "contents": "Item two", "priority": 300 },
+        { "contents": "Item three", "sha256": "nope" },
+        { "contents": "Item four", "delay_ms": 10, "deliver_at": 10 }
+    ]"#;
+    let (status, body) = post("/batch?atomic=true", batch);
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(body["data"][0]["status"], "not queued");
+    assert_eq!(body["data"][1]["reason"], "invalid priority");
+    assert_eq!(body["data"][2]["reason"], "invalid sha256");
+    assert_eq!(body["data"][3]["reason"], "set either deliver_at or delay_ms, not both");
+    assert_eq!(in_queue(), started);
+
+    // Otherwise the valid messages are queued.
+    let (status, body) = post("/batch", batch);
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(body["accepted"], 1);
+    assert_eq!(body["rejected"], 3);
+    assert_eq!(body["data"][0]["status"], "accepted");
+    assert_eq!(body["data"][0]["data"]["priority"], 10);
+    assert_eq!(body["data"][1]["code"], 400);
+    assert_eq!(in_queue(), started + 1);
+
+    // Duplicates are detected within the batch and against the queue.
+    let batch = r#"[
+        { "contents": "Item one" },
+        { "contents": "Item five", "priority": 50, "idempotency_key": "five" },
+        { "contents": "Item five" },
+        { "contents": "Item six", "idempotency_key": "five" }
+    ]"#;
+    let (status, body) = post("/batch?atomic=true", batch);
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(body["accepted"], 1);
+    assert_eq!(body["duplicates"], 3);
+    let uuid = body["data"][1]["data"]["uuid"].clone();
+    assert_eq!(body["data"][0]["status"], "duplicate");
+    assert_eq!(body["data"][2]["data"]["uuid"], uuid);
+    assert_eq!(body["data"][3]["data"]["uuid"], uuid);
+    assert_eq!(in_queue(), started + 2);
+
+    // The idempotency key is shared with single messages, and is checked first, so a
+    // repeated key gets the original response even if the message is now invalid.
+    let (status, body) = post("/batch", r#"[{ "contents": "Item six", "priority": 300, "idempotency_key": "five" }]"#);
+    assert_eq!(status, Status::Accepted);
+    assert_eq!(body["data"][0]["status"], "accepted");
+    assert_eq!(body["data"][0]["data"]["uuid"], uuid);
+    let mut res = client.post("/")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", "five"))
+        .body(r#"{ "contents": "Item six" }"#)
+        .dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["uuid"], uuid);
+
+    // Messages are delivered by priority, as if queued one at a time.
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["contents"], "Item five");
+    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["contents"], "Item one");
+    assert_eq!(in_queue(), started);
+
+    let (status, _) = post("/queues/nothing/batch", "[]");
+    assert_eq!(status, Status::NotFound);
+
+    // Without deduplication, the same contents are queued twice, as they would be if
+    // they were sent separately.
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    let mut res = client.post("/batch")
+        .header(ContentType::JSON)
+        .body(r#"[{ "contents": "Item seven" }, { "contents": "Item seven" }]"#)
+        .dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["accepted"], 2);
+    assert_eq!(body["duplicates"], 0);
+    assert_eq!(body["data"][0]["status"], "accepted");
+    assert_eq!(body["data"][1]["status"], "accepted");
+    assert_ne!(body["data"][1]["data"]["uuid"], body["data"][0]["data"]["uuid"]);
+    for _ in 0..2 {
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], "Item seven");
+    }
+}
+
+#[test]
+fn batch_dequeue() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    let counters = || -> (usize, usize, usize) {
+        let counters = COUNTERS.lock().unwrap();
+        (
+            counters.proxied.load(Ordering::Relaxed),
+            counters.in_queue.load(Ordering::Relaxed),
+            counters.bytes.load(Ordering::Relaxed),
+        )
+    };
+    let (proxied, in_queue, bytes) = counters();
+
+    let batch = r#"[
+        { "contents": "12345", "priority": 10 },
+        { "contents": "1234567890", "priority": 50 },
+        { "contents": "123", "priority": 30 },
+        { "contents": "1234", "priority": 20 },
+        { "contents": "12", "priority": 40 }
+    ]"#;
+    client.post("/batch").header(ContentType::JSON).body(batch).dispatch();
+    let get = |url: &str| -> serde_json::Value {
+        let mut res = client.get(url).header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        serde_json::from_str(&res.body_string().unwrap()).unwrap()
+    };
+    let contents = |body: &serde_json::Value| -> Vec<String> {
+        body["data"].as_array().unwrap().iter().map(|m| m["contents"].as_str().unwrap().to_string()).collect()
+    };
+
+    // Messages are returned highest priority first.
+    let body = get("/?max=2");
+    assert_eq!(body["count"], 2);
+    assert_eq!(contents(&body), vec!["1234567890", "12"]);
+    let (now_proxied, now_in_queue, now_bytes) = counters();
+    assert_eq!(now_proxied, proxied + 2);
+    assert_eq!(now_in_queue, in_queue + 3);
+    assert!(now_bytes > bytes);
+
+    // The batch stops before the next message would go over the budget.
+    let body = get("/?max_bytes=6");
+    assert_eq!(contents(&body), vec!["123"]);
+    let body = get("/?max=5&max_bytes=9");
+    assert_eq!(contents(&body), vec!["1234", "12345"]);
+    assert_eq!(body["bytes"], 9);
+    assert_eq!(counters(), (proxied + 5, in_queue, bytes));
+
+    // Nothing left.
+    let res = client.get("/?max=5").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // The first message is returned even if it is over the budget.
+    client.post("/").header(ContentType::JSON).body(r#"{ "contents": "1234567890" }"#).dispatch();
+    let body = get("/queues/default?max_bytes=1");
+    assert_eq!(contents(&body), vec!["1234567890"]);
+}
+
+#[test]
+fn long_poll() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+
+    // An empty queue is waited on until the timeout.
+    let started = Instant::now();
+    let res = client.get("/?wait=1").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert!(started.elapsed() >= Duration::from_millis(900));
+
+    // A message queued while waiting is returned right away.
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(200));
+            client.post("/").header(ContentType::JSON).body(r#"{ "contents": "Item one" }"#).dispatch();
+        });
+        let mut res = client.get("/?wait=10").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+        assert_eq!(body["data"]["contents"], "Item one");
+    });
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // So is a scheduled message once it is due, along with batches.
+    client.post("/").header(ContentType::JSON).body(r#"{ "contents": "Item two", "delay_ms": 300 }"#).dispatch();
+    let started = Instant::now();
+    let mut res = client.get("/queues/default?wait=10&max=5").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"][0]["contents"], "Item two");
+    assert!(started.elapsed() >= Duration::from_millis(250));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The proxy and notify loops wait the same way.
+    let generation = wakeup::generation();
+    assert!(!wakeup::wait(generation, Duration::from_millis(10)));
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            wakeup::notify();
+        });
+        assert!(wakeup::wait(generation, Duration::from_secs(10)));
+    });
+}
+
+#[test]
+fn waiting_requests_are_limited() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::set_var("ROCKET_WORKERS", "4");
+    std::env::set_var("ROCKET_MAX_WAITING_REQUESTS", "10");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_WORKERS");
+    std::env::remove_var("ROCKET_MAX_WAITING_REQUESTS");
+
+    // At most 3 of the 4 workers can wait, streams included.
+    let streams: Vec<_> = (0..3).map(|_| client.get("/stream").dispatch()).collect();
+    assert!(streams.iter().all(|res| res.status() == Status::Ok));
+    let res = client.get("/stream").dispatch();
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+
+    // Extra long polls are answered straight away.
+    let started = Instant::now();
+    let res = client.get("/?wait=5").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    // Once a stream closes, its worker can be used to wait again.
+    drop(streams);
+    let started = Instant::now();
+    let res = client.get("/?wait=1").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn peek_and_list_messages() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    let get = |url: &str| -> serde_json::Value {
+        let mut res = client.get(url).header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        serde_json::from_str(&res.body_string().unwrap()).unwrap()
+    };
+    let res = client.get("/peek").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    let batch = r#"[
+        { "contents": "Item one", "priority": 10 },
+        { "contents": "Item two", "priority": 50 },
+        { "contents": "Item three", "priority": 30 },
+        { "contents": "Item four", "priority": 30 },
+        { "contents": "Item five", "priority": 5 },
+        { "contents": "Item six", "priority": 90, "delay_ms": 60000 }
+    ]"#;
+    client.post("/batch").header(ContentType::JSON).body(batch).dispatch();
+
+    // Peeking doesn't remove the message, and only shows contents when asked.
+    for _ in 0..2 {
+        let body = get("/peek");
+        assert_eq!(body["data"]["priority"], 50);
+        assert!(body["data"]["contents"].is_null());
+        assert!(body["data"]["size_in_bytes"].as_u64().unwrap() > 0);
+        assert_eq!(body["data"]["delivery_attempts"], 0);
+    }
+    let body = get("/queues/default/peek?contents=true");
+    assert_eq!(body["data"]["contents"], "Item two");
+
+    // Messages are listed in the order they will be delivered, scheduled ones last.
+    let contents = |body: &serde_json::Value| -> Vec<String> {
+        body["data"].as_array().unwrap().iter().map(|m| m["contents"].as_str().unwrap().to_string()).collect()
+    };
+    let body = get("/messages?contents=true");
+    assert_eq!(body["in_queue"], 6);
+    assert_eq!(contents(&body), vec!["Item two", "Item three", "Item four", "Item one", "Item five", "Item six"]);
+    let body = get("/messages?contents=true&offset=1&limit=2");
+    assert_eq!(body["count"], 2);
+    assert_eq!(contents(&body), vec!["Item three", "Item four"]);
+    let body = get("/messages?contents=true&min_priority=30");
+    assert_eq!(contents(&body), vec!["Item two", "Item three", "Item four", "Item six"]);
+    let body = get("/messages?queue=default&offset=10");
+    assert_eq!(body["count"], 0);
+
+    let res = client.get("/messages?queue=nothing").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+    let res = client.get("/queues/nothing/peek").header(ContentType::JSON).dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    // Contents can be hidden from operators entirely.
+    std::env::set_var("ROCKET_REDACT_CONTENTS", "true");
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    std::env::remove_var("ROCKET_REDACT_CONTENTS");
+    client.post("/").header(ContentType::JSON).body(r#"{ "contents": "Secret" }"#).dispatch();
+    let mut res = client.get("/peek?contents=true").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"]["contents"], "[redacted]");
+    let mut res = client.get("/messages?contents=true").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["data"][0]["contents"], "[redacted]");
+}
+
+#[test]
+fn memory_store_lists_overflow_in_order() {
+    let directory = std::env::temp_dir().join(format!("rqueue-test-{}", Uuid::new_v4()));
+    let disk = DiskStore::create(directory.to_str().unwrap(), 1024 * 1024).unwrap();
+    // Room for two messages in memory, the rest overflow to disk.
+    let mut store = MemoryStore::new(250, Some(disk));
+    for (contents, priority) in &[("Item one", 10), ("Item two", 40), ("Item three", 20), ("Item four", 30), ("Item five", 40)] {
+        let message = InternalMessage {
+            contents: contents.to_string(),
+            priority: *priority,
+            size_in_bytes: 100,
+            uuid: Uuid::new_v4(),
+            sequence: store::next_sequence(),
+            ..Default::default()
+        };
+        store.push(message).unwrap();
+    }
+    assert!(store.overflow().0 > 0);
+    let listed: Vec<String> = store.list(0, 10, 0).into_iter().map(|m| m.contents).collect();
+    assert_eq!(listed, vec!["Item two", "Item five", "Item four", "Item three", "Item one"]);
+    let listed: Vec<String> = store.list(1, 3, 20).into_iter().map(|m| m.contents).collect();
+    assert_eq!(listed, vec!["Item five", "Item four", "Item three"]);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+// Read the next Server-Sent Event from a stream.
+fn read_event(reader: &mut dyn Read) -> String {
+    let mut event = Vec::new();
+    let mut buffer = [0; 256];
+    while !event.ends_with(b"\n\n") {
+        match reader.read(&mut buffer) {
+            Ok(0) => panic!("stream ended"),
+            Ok(n) => event.extend_from_slice(&buffer[..n]),
+            // The stream asks for what it has sent so far to be flushed.
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(e) => panic!("failed to read stream: {}", e),
+        }
+    }
+    String::from_utf8(event).unwrap()
+}
+
+#[test]
+fn stream_messages() {
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let client = Client::new(rocket(time_since_epoch())).unwrap();
+    let leased = || COUNTERS.lock().unwrap().leased.load(Ordering::Relaxed);
+    let started_leased = leased();
+
+    let batch = r#"[
+        { "contents": "Item one", "priority": 10 },
+        { "contents": "Item two", "priority": 30 },
+        { "contents": "Item three", "priority": 20 }
+    ]"#;
+    client.post("/batch").header(ContentType::JSON).body(batch).dispatch();
+
+    let res = client.get("/queues/nothing/stream").dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+
+    let mut res = client.get("/stream?credits=2").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.content_type(), Some(ContentType::new("text", "event-stream")));
+    let data = |event: &str| -> serde_json::Value {
+        let line = event.lines().find(|line| line.starts_with("data: ")).unwrap();
+        serde_json::from_str(&line["data: ".len()..]).unwrap()
+    };
+    {
+        let reader = res.body().unwrap().into_inner();
+
+        // Messages are sent in priority order, as long as there is credit.
+        let first = data(&read_event(reader));
+        assert_eq!(first["contents"], "Item two");
+        assert!(first["receipt"].is_string());
+        let second = data(&read_event(reader));
+        assert_eq!(second["contents"], "Item three");
+        assert_eq!(leased(), started_leased + 2);
+
+        // Acknowledging a message gives credit for the next one.
+        let ack = client.post(format!("/ack/{}", first["receipt"].as_str().unwrap())).dispatch();
+        assert_eq!(ack.status(), Status::Ok);
+        let third = data(&read_event(reader));
+        assert_eq!(third["contents"], "Item one");
+    }
+
+    // Disconnecting returns unacknowledged messages to the queue.
+    drop(res);
+    assert_eq!(leased(), started_leased);
+    let mut res = client.get("/messages?contents=true").header(ContentType::JSON).dispatch();
+    let body: serde_json::Value = serde_json::from_str(&res.body_string().unwrap()).unwrap();
+    assert_eq!(body["count"], 2);
+    assert_eq!(body["data"][0]["contents"], "Item three");
+    assert_eq!(body["data"][0]["delivery_attempts"], 1);
+    assert_eq!(body["data"][1]["contents"], "Item one");
+}
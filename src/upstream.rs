@@ -0,0 +1,194 @@
+use crate::Timestamp;
+
+// By default an upstream is ejected after 5 consecutive failures.
+pub(crate) const DEFAULT_FAILURE_LIMIT: usize = 5;
+// By default an ejected upstream isn't used again for 30 seconds, in milliseconds.
+pub(crate) const DEFAULT_EJECTION_TIME: Timestamp = 30_000;
+
+// How the proxy chooses which upstream receives each message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Strategy {
+    // Each upstream in turn.
+    RoundRobin,
+    // Each upstream in turn, in proportion to its weight.
+    Weighted,
+    // The first upstream that hasn't been ejected.
+    Failover,
+}
+
+impl Strategy {
+    pub(crate) fn parse(name: &str) -> Option<Strategy> {
+        match name {
+            "round-robin" => Some(Strategy::RoundRobin),
+            "weighted" => Some(Strategy::Weighted),
+            "failover" => Some(Strategy::Failover),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::Weighted => "weighted",
+            Strategy::Failover => "failover",
+        }
+    }
+}
+
+// A server that messages are proxied to, and how deliveries to it have gone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Upstream {
+    pub(crate) url: String,
+    pub(crate) weight: usize,
+    // Used by the weighted strategy to spread each upstream's turns out evenly.
+    current_weight: i64,
+    consecutive_failures: usize,
+    // Not used until then, either because it was ejected or because it asked us to
+    // wait with Retry-After.
+    unavailable_until: Timestamp,
+    pub(crate) delivered: usize,
+    pub(crate) rejected: usize,
+    pub(crate) failed: usize,
+    pub(crate) ejections: usize,
+}
+
+impl Upstream {
+    pub(crate) fn new(url: &str, weight: usize) -> Upstream {
+        Upstream {
+            url: url.to_string(),
+            weight,
+            ..Default::default()
+        }
+    }
+
+    fn is_available(&self, now: Timestamp) -> bool {
+        self.unavailable_until <= now
+    }
+}
+
+// The upstreams messages are proxied to. Upstreams that fail `failure_limit` times in a
+// row are ejected for `ejection_time`, and then tried again; another failure ejects
+// them straight away.
+pub(crate) struct Upstreams {
+    pub(crate) strategy: Strategy,
+    pub(crate) upstreams: Vec<Upstream>,
+    // Zero disables ejection.
+    pub(crate) failure_limit: usize,
+    // How long upstreams are ejected for, in milliseconds.
+    pub(crate) ejection_time: Timestamp,
+    // The upstream chosen last by the round-robin strategy.
+    last: usize,
+}
+
+impl Default for Upstreams {
+    fn default() -> Upstreams {
+        Upstreams {
+            strategy: Strategy::RoundRobin,
+            upstreams: Vec::new(),
+            failure_limit: DEFAULT_FAILURE_LIMIT,
+            ejection_time: DEFAULT_EJECTION_TIME,
+            last: usize::MAX,
+        }
+    }
+}
+
+impl Upstreams {
+    pub(crate) fn new(strategy: Strategy, upstreams: Vec<Upstream>) -> Upstreams {
+        Upstreams {
+            strategy,
+            upstreams,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn get(&self, index: usize) -> &Upstream {
+        &self.upstreams[index]
+    }
+
+    // Whether any upstream can be sent a message now.
+    pub(crate) fn is_available(&self, now: Timestamp) -> bool {
+        self.upstreams.iter().any(|u| u.is_available(now))
+    }
+
+    // How long until an upstream can be sent a message, in milliseconds.
+    pub(crate) fn wait_time(&self, now: Timestamp) -> Timestamp {
+        self.upstreams.iter()
+            .map(|u| u.unavailable_until.saturating_sub(now))
+            .min()
+            .unwrap_or(0)
+    }
+
+    // The index of the upstream that should receive the next message, if any are
+    // available.
+    pub(crate) fn choose(&mut self, now: Timestamp) -> Option<usize> {
+        let count = self.upstreams.len();
+        match self.strategy {
+            Strategy::Failover => self.upstreams.iter().position(|u| u.is_available(now)),
+            Strategy::RoundRobin => {
+                let index = (1..=count)
+                    .map(|offset| self.last.wrapping_add(offset) % count)
+                    .find(|&i| self.upstreams[i].is_available(now))?;
+                self.last = index;
+                Some(index)
+            }
+            Strategy::Weighted => {
+                // Smooth weighted round-robin: every available upstream gains its
+                // weight, and the one with the most gives up the total.
+                let mut total = 0;
+                let mut chosen: Option<(usize, i64)> = None;
+                for (i, upstream) in self.upstreams.iter_mut().enumerate() {
+                    if !upstream.is_available(now) {
+                        continue;
+                    }
+                    upstream.current_weight += upstream.weight as i64;
+                    total += upstream.weight as i64;
+                    if chosen.is_none_or(|(_, weight)| upstream.current_weight > weight) {
+                        chosen = Some((i, upstream.current_weight));
+                    }
+                }
+                let (index, _) = chosen?;
+                self.upstreams[index].current_weight -= total;
+                Some(index)
+            }
+        }
+    }
+
+    // The upstream accepted the message.
+    pub(crate) fn delivered(&mut self, index: usize) {
+        let upstream = &mut self.upstreams[index];
+        upstream.delivered += 1;
+        upstream.consecutive_failures = 0;
+    }
+
+    // The upstream is working, but won't ever accept the message.
+    pub(crate) fn rejected(&mut self, index: usize) {
+        let upstream = &mut self.upstreams[index];
+        upstream.rejected += 1;
+        upstream.consecutive_failures = 0;
+    }
+
+    // The upstream asked us to wait this many milliseconds before sending anything else.
+    pub(crate) fn retry_after(&mut self, index: usize, delay: Timestamp, now: Timestamp) {
+        let upstream = &mut self.upstreams[index];
+        upstream.unavailable_until = upstream.unavailable_until.max(now + delay);
+    }
+
+    // Delivery to the upstream failed, returns true if it has been ejected.
+    pub(crate) fn failed(&mut self, index: usize, now: Timestamp) -> bool {
+        let failure_limit = self.failure_limit;
+        let ejection_time = self.ejection_time;
+        let upstream = &mut self.upstreams[index];
+        upstream.failed += 1;
+        upstream.consecutive_failures += 1;
+        if failure_limit == 0 || upstream.consecutive_failures < failure_limit {
+            return false;
+        }
+        upstream.ejections += 1;
+        upstream.unavailable_until = upstream.unavailable_until.max(now + ejection_time);
+        true
+    }
+
+    pub(crate) fn consecutive_failures(&self, index: usize) -> usize {
+        self.upstreams[index].consecutive_failures
+    }
+}
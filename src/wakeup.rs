@@ -0,0 +1,64 @@
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crate::WAKEUP;
+
+// Wakes anything waiting for a message when one is added to a queue. Waiters note
+// the generation before checking the queue, so a message added after they looked
+// still wakes them.
+#[derive(Default)]
+pub(crate) struct Wakeup {
+    generation: Mutex<u64>,
+    condvar: Condvar,
+    // Requests waiting for messages, each holding one of Rocket's workers, and how many
+    // are allowed at once.
+    waiters: AtomicUsize,
+    max_waiters: AtomicUsize,
+}
+
+// A request's place among the requests waiting for messages, given up when dropped.
+pub(crate) struct Waiter;
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        WAKEUP.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// The current generation, to be passed to `wait`.
+pub(crate) fn generation() -> u64 {
+    *WAKEUP.generation.lock().unwrap()
+}
+
+// Wake everything waiting for a message.
+pub(crate) fn notify() {
+    let mut generation = WAKEUP.generation.lock().unwrap();
+    *generation = generation.wrapping_add(1);
+    WAKEUP.condvar.notify_all();
+}
+
+// Wait until a message is added after `generation`, or for the timeout, returning
+// whether a message was added.
+pub(crate) fn wait(generation: u64, timeout: Duration) -> bool {
+    let current = WAKEUP.generation.lock().unwrap();
+    let (current, _) = WAKEUP.condvar
+        .wait_timeout_while(current, timeout, |current| *current == generation)
+        .unwrap();
+    *current != generation
+}
+
+// Limit how many requests can wait for messages at once, so that some of Rocket's
+// workers are always free to handle other requests.
+pub(crate) fn set_max_waiters(max_waiters: usize) {
+    WAKEUP.max_waiters.store(max_waiters, Ordering::SeqCst);
+}
+
+// A place for a request to wait for messages, unless too many already are.
+pub(crate) fn waiter() -> Option<Waiter> {
+    let max_waiters = WAKEUP.max_waiters.load(Ordering::SeqCst);
+    WAKEUP.waiters
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| if n < max_waiters { Some(n + 1) } else { None })
+        .ok()
+        .map(|_| Waiter)
+}
@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+use size::{Base, Size, Style};
+
+use crate::{COUNTERS, QUEUE, DELIVERY, LEASES, DEAD_LETTERS, WAL, milliseconds_since_timestamp, dedup, InternalMessage};
+use crate::store::DEFAULT_QUEUE;
+use crate::dead_letter::DeadLetter;
+
+// By default the log is compacted once it grows past 64 MiB.
+pub(crate) const DEFAULT_COMPACTION_SIZE: u64 = 1024 * 1024 * 64;
+// How often to check whether the log needs compacting, in seconds.
+const COMPACTION_SWEEP_DELAY: u64 = 10;
+
+// Every change to the queue is appended to the log as a single line of JSON. The
+// enum is externally tagged as serde can't buffer the u128 arrival timestamp.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum WalEntry {
+    // Boxed, as messages are much larger than removals.
+    Push { message: Box<InternalMessage> },
+    Remove { uuid: Uuid },
+    // The message stays in the dead-letter queue until it is requeued or purged.
+    #[serde(rename = "dead_letter")]
+    DeadLetter { letter: Box<DeadLetter> },
+}
+
+// What's left after replaying the log.
+#[derive(Debug, Default)]
+pub(crate) struct Replayed {
+    // In the order they were originally pushed.
+    pub(crate) messages: Vec<InternalMessage>,
+    // Oldest first.
+    pub(crate) dead_letters: Vec<DeadLetter>,
+}
+
+// Append-only log of queue operations, disabled if no path is configured.
+#[derive(Default)]
+pub(crate) struct WriteAheadLog {
+    path: String,
+    file: Option<File>,
+    // How many bytes are in the log, and how many were left after it was last compacted.
+    size: u64,
+    compacted_size: u64,
+    // The log is compacted once it grows past this size, and to twice its compacted size.
+    pub(crate) compaction_size: u64,
+}
+
+impl WriteAheadLog {
+    pub(crate) fn open(path: &str) -> io::Result<WriteAheadLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let size = file.metadata()?.len();
+        Ok(WriteAheadLog {
+            path: path.to_string(),
+            file: Some(file),
+            size,
+            compacted_size: size,
+            compaction_size: DEFAULT_COMPACTION_SIZE,
+        })
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.path
+    }
+
+    // Whether the log has grown enough since it was last compacted to compact it again.
+    pub(crate) fn needs_compaction(&self) -> bool {
+        self.is_enabled() && self.size > self.compaction_size && self.size > self.compacted_size * 2
+    }
+
+    // Write an entry and flush it to disk before returning.
+    pub(crate) fn append(&mut self, entry: &WalEntry) -> io::Result<()> {
+        match self.file.as_mut() {
+            None => Ok(()),
+            Some(file) => {
+                let mut line = serde_json::to_string(entry)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+                self.size += line.len() as u64;
+                file.sync_data()
+            }
+        }
+    }
+
+    // Replace the log with one that only contains `messages` and `dead_letters`.
+    pub(crate) fn rewrite(&mut self, messages: &[InternalMessage], dead_letters: &[DeadLetter]) -> io::Result<()> {
+        // Close the log before replacing it with the compacted version.
+        self.file = None;
+        let compacted = compact(&self.path, messages, dead_letters);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.compacted_size = self.size;
+        self.file = Some(file);
+        compacted
+    }
+}
+
+// Record a message that has been accepted into the queue.
+pub(crate) fn log_push(message: &InternalMessage) -> io::Result<()> {
+    let mut wal = WAL.lock().unwrap();
+    wal.append(&WalEntry::Push { message: Box::new(message.clone()) })
+}
+
+// Record a change to a queued message by logging it again, which replaces the
+// earlier entry when the log is replayed.
+pub(crate) fn log_update(message: &InternalMessage, server_started: Duration) {
+    let mut wal = WAL.lock().unwrap();
+    if let Err(e) = wal.append(&WalEntry::Push { message: Box::new(message.clone()) }) {
+        log::error!("{}|failed to log change to {} to '{}': {}",
+            milliseconds_since_timestamp(server_started),
+            message.uuid,
+            wal.path,
+            e,
+        );
+    }
+}
+
+// Record a message that has been moved to the dead-letter queue, replacing its
+// earlier entries.
+pub(crate) fn log_dead_letter(letter: &DeadLetter, server_started: Duration) {
+    let mut wal = WAL.lock().unwrap();
+    if let Err(e) = wal.append(&WalEntry::DeadLetter { letter: Box::new(letter.clone()) }) {
+        log::error!("{}|failed to log dead letter {} to '{}': {}",
+            milliseconds_since_timestamp(server_started),
+            letter.message.uuid,
+            wal.path,
+            e,
+        );
+    }
+}
+
+// Record a message that has permanently left the queue or the dead-letter queue.
+pub(crate) fn log_remove(uuid: Uuid, server_started: Duration) {
+    let mut wal = WAL.lock().unwrap();
+    if let Err(e) = wal.append(&WalEntry::Remove { uuid }) {
+        log::error!("{}|failed to log removal of {} to '{}': {}",
+            milliseconds_since_timestamp(server_started),
+            uuid,
+            wal.path,
+            e,
+        );
+    }
+}
+
+// Read the log at `path` and return all messages and dead letters that were never
+// removed. A truncated final line (for example from a crash in the middle of a
+// write) is ignored.
+pub(crate) fn replay(path: &str) -> io::Result<Replayed> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Replayed::default()),
+        Err(e) => return Err(e),
+    };
+
+    let mut order: Vec<Uuid> = Vec::new();
+    let mut live: HashMap<Uuid, InternalMessage> = HashMap::new();
+    let mut dead: HashMap<Uuid, DeadLetter> = HashMap::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(WalEntry::Push { message }) => {
+                // A message logged again keeps its place, but takes the new contents. A
+                // dead letter logged again has been requeued.
+                let uuid = message.uuid;
+                dead.remove(&uuid);
+                if live.insert(uuid, *message).is_none() {
+                    order.push(uuid);
+                }
+            }
+            Ok(WalEntry::Remove { uuid }) => {
+                live.remove(&uuid);
+                dead.remove(&uuid);
+            }
+            Ok(WalEntry::DeadLetter { letter }) => {
+                live.remove(&letter.message.uuid);
+                dead.insert(letter.message.uuid, *letter);
+            }
+            Err(e) => {
+                log::warn!("ignoring invalid entry on line {} of '{}': {}", number + 1, path, e);
+            }
+        }
+    }
+
+    let mut dead_letters: Vec<DeadLetter> = dead.into_values().collect();
+    dead_letters.sort_by_key(|l| (l.died, l.message.uuid));
+    Ok(Replayed {
+        messages: order.iter().filter_map(|uuid| live.remove(uuid)).collect(),
+        dead_letters,
+    })
+}
+
+// Rewrite the log at `path` so it only contains the provided messages and dead letters.
+pub(crate) fn compact(path: &str, messages: &[InternalMessage], dead_letters: &[DeadLetter]) -> io::Result<()> {
+    let temporary_path = format!("{}.tmp", path);
+    {
+        let mut file = File::create(&temporary_path)?;
+        let entries = messages.iter()
+            .map(|message| WalEntry::Push { message: Box::new(message.clone()) })
+            .chain(dead_letters.iter().map(|letter| WalEntry::DeadLetter { letter: Box::new(letter.clone()) }));
+        for entry in entries {
+            let mut line = serde_json::to_string(&entry)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&temporary_path, path)
+}
+
+// Rebuild the queue from the write-ahead log, then compact the log.
+pub(crate) fn restore(server_started: Duration) {
+    let mut wal = WAL.lock().unwrap();
+    if !wal.is_enabled() {
+        return;
+    }
+    let path = wal.path.clone();
+
+    let replayed = match replay(&path) {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("{}|failed to replay write-ahead log '{}': {}",
+                milliseconds_since_timestamp(server_started),
+                path,
+                e,
+            );
+            return;
+        }
+    };
+
+    if let Err(e) = wal.rewrite(&replayed.messages, &replayed.dead_letters) {
+        log::error!("{}|failed to compact write-ahead log '{}': {}",
+            milliseconds_since_timestamp(server_started),
+            path,
+            e,
+        );
+    }
+    // Always grab the queue locks before the log lock.
+    drop(wal);
+
+    let counters = COUNTERS.lock().unwrap();
+    let mut queue = QUEUE.lock().expect("queue lock");
+    let mut restored = 0;
+    let mut restored_bytes = 0;
+    for mut message in replayed.messages {
+        let size_of_request = message.size_in_bytes;
+        let uuid = message.uuid;
+        // The disk backend restores messages that were still queued itself, the log
+        // only adds those that were being delivered, leased or scheduled.
+        if queue.find(&uuid).is_some() {
+            continue;
+        }
+        if !queue.contains(&message.queue) {
+            log::warn!("{}|queue '{}' is no longer configured, restoring message {} to the default queue",
+                milliseconds_since_timestamp(server_started),
+                message.queue,
+                uuid,
+            );
+            message.queue = DEFAULT_QUEUE.to_string();
+        }
+        let name = message.queue.clone();
+        dedup::queued(&message);
+        if let Err(e) = queue.push(message) {
+            log::error!("{}|failed to restore message {}: {}",
+                milliseconds_since_timestamp(server_started),
+                uuid,
+                e,
+            );
+            continue;
+        }
+        counters.queue_restored(&name, size_of_request);
+        restored += 1;
+        restored_bytes += size_of_request;
+    }
+    counters.in_queue.fetch_add(restored, Ordering::Relaxed);
+    counters.bytes.fetch_add(restored_bytes, Ordering::Relaxed);
+
+    let restored_dead_letters = replayed.dead_letters.len();
+    let mut dead_letters = DEAD_LETTERS.lock().unwrap();
+    for letter in replayed.dead_letters {
+        if dead_letters.insert(letter).is_none() {
+            counters.dead_letters.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    drop(dead_letters);
+
+    log::info!("{}|restored {} messages ({}) and {} dead letters from write-ahead log '{}'",
+        milliseconds_since_timestamp(server_started),
+        restored,
+        Size::Bytes(restored_bytes).to_string(Base::Base10, Style::Abbreviated),
+        restored_dead_letters,
+        path,
+    );
+}
+
+// Rewrite the log so it only contains the messages that are still queued, leased or
+// in the dead-letter queue.
+pub(crate) fn compact_queue(server_started: Duration) {
+    // Messages being delivered are only in the log, so wait for the delivery to finish.
+    let _delivery = DELIVERY.lock().unwrap();
+    let queue = QUEUE.lock().expect("queue lock");
+    let leases = LEASES.lock().unwrap();
+    let dead_letters = DEAD_LETTERS.lock().unwrap();
+    let mut wal = WAL.lock().unwrap();
+    if !wal.is_enabled() {
+        return;
+    }
+    let mut messages = leases.messages();
+    messages.extend(queue.messages());
+    let dead_letters: Vec<DeadLetter> = dead_letters.list().into_iter().cloned().collect();
+    let size = wal.size;
+    match wal.rewrite(&messages, &dead_letters) {
+        Ok(_) => {
+            log::info!("{}|compacted write-ahead log '{}' from {} to {}, {} messages and {} dead letters",
+                milliseconds_since_timestamp(server_started),
+                wal.path,
+                Size::Bytes(size).to_string(Base::Base10, Style::Abbreviated),
+                Size::Bytes(wal.size).to_string(Base::Base10, Style::Abbreviated),
+                messages.len(),
+                dead_letters.len(),
+            );
+        }
+        Err(e) => {
+            log::error!("{}|failed to compact write-ahead log '{}': {}",
+                milliseconds_since_timestamp(server_started),
+                wal.path,
+                e,
+            );
+        }
+    }
+}
+
+// Periodically compact the log once it has grown too large.
+pub fn compaction_loop(server_started: Duration) {
+    loop {
+        thread::sleep(Duration::from_secs(COMPACTION_SWEEP_DELAY));
+        // Always grab the queue locks before the log lock.
+        let needs_compaction = WAL.lock().unwrap().needs_compaction();
+        if needs_compaction {
+            compact_queue(server_started);
+        }
+    }
+}